@@ -3,6 +3,7 @@ use uuid::Uuid;
 
 #[cfg(feature = "scrape")]
 pub mod scrape;
+pub mod upstream;
 
 /// A top level category of kasetophono
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -31,7 +32,7 @@ pub enum SubcategoryKind {
     Cassette(String),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Cassette {
     pub uuid: Uuid,
     pub name: String,
@@ -41,6 +42,12 @@ pub struct Cassette {
     pub yt_url: String,
     pub videos: Vec<Song>,
     pub image_url: Option<String>,
+    /// The text of the post, without its markup
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Who put the cassette together, i.e. the author of the post
+    #[serde(default)]
+    pub curator: Option<String>,
     pub labels: Vec<String>,
     pub subcategories: Vec<Subcategory>,
     pub created_at: String,
@@ -1,5 +1,6 @@
 use crate::scrape::blogger;
 use scraper::{Html, Selector};
+use std::path::Path;
 use uuid::Uuid;
 use youtube_dl::{YoutubeDl, YoutubeDlOutput};
 
@@ -42,6 +43,13 @@ impl Cassette {
                 .map(|c| c.term.into_owned())
                 .collect::<Vec<_>>();
 
+            // Blogger names authors without a public profile "Unknown"
+            let curator = entry
+                .author
+                .first()
+                .map(|author| author.name.t.trim().to_string())
+                .filter(|name| !name.is_empty() && name != "Unknown");
+
             let image = content
                 .select(&image_selector)
                 .next()
@@ -55,6 +63,8 @@ impl Cassette {
                 subcategories: vec![],
                 labels,
                 image_url: image.map(|s| s.to_string()),
+                description: description(&content),
+                curator,
                 url: url.into_owned(),
                 yt_url: yt_url.to_string(),
                 videos: vec![],
@@ -77,7 +87,15 @@ impl Cassette {
     }
 
     pub fn fill_songs(&mut self) -> Result<(), anyhow::Error> {
-        let output = YoutubeDl::new(&self.yt_url).flat_playlist(true).run()?;
+        self.fill_songs_with("youtube-dl")
+    }
+
+    /// Like `fill_songs`, but using the given youtube-dl compatible executable, e.g. yt-dlp
+    pub fn fill_songs_with(&mut self, youtube_dl: impl AsRef<Path>) -> Result<(), anyhow::Error> {
+        let output = YoutubeDl::new(&self.yt_url)
+            .youtube_dl_path(youtube_dl)
+            .flat_playlist(true)
+            .run()?;
 
         if let YoutubeDlOutput::Playlist(playlist) = output {
             self.videos = playlist
@@ -98,6 +116,16 @@ impl Cassette {
     }
 }
 
+/// The text of a post, with its whitespace collapsed
+fn description(content: &Html) -> Option<String> {
+    let words: Vec<&str> = content
+        .root_element()
+        .text()
+        .flat_map(str::split_whitespace)
+        .collect();
+    Some(words.join(" ")).filter(|description| !description.is_empty())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -105,19 +133,23 @@ mod test {
     #[test]
     fn youtube_dl() {
         let mut c = Cassette {
-            uuid: Default::default(),
-            name: Default::default(),
-            safe_name: Default::default(),
-            path: Default::default(),
-            subcategories: vec![],
-            labels: Default::default(),
-            image_url: Default::default(),
-            url: Default::default(),
             yt_url: "https://www.youtube.com/watch?v=va-EudnxtAc&list=PLSRDGXudTSm8FuEJEeix05FqOVCMNvlJI".to_string(),
-            videos: vec![],
-            created_at: Default::default(),
+            ..Default::default()
         };
 
         c.fill_songs();
     }
+
+    #[test]
+    fn post_description() {
+        let content = Html::parse_fragment(
+            "<div><img src=\"cover.jpg\"/><br/>Songs for <b>late</b>\n  nights.</div>\
+             <iframe src=\"https://www.youtube.com/embed?list=PL\"></iframe>",
+        );
+        assert_eq!(
+            description(&content).as_deref(),
+            Some("Songs for late nights.")
+        );
+        assert_eq!(description(&Html::parse_fragment("<br/> ")), None);
+    }
 }
@@ -1,19 +1,44 @@
+use std::path::Path;
 use youtube_dl::{YoutubeDl, YoutubeDlOutput};
 
 use crate::Song;
 
+/// An audio only stream of a song
+#[derive(Clone, Debug)]
+pub struct AudioStream {
+    pub url: String,
+    /// The youtube-dl format id, e.g. `251`
+    pub format_id: String,
+}
+
 impl Song {
     pub fn audio_url(&self) -> Option<String> {
-        match YoutubeDl::new(&self.id).run().unwrap() {
+        let stream = self.audio_stream_with("youtube-dl").unwrap();
+        stream.map(|stream| stream.url)
+    }
+
+    /// Like `audio_url`, but using the given youtube-dl compatible executable and also returning
+    /// which format the stream is in
+    pub fn audio_stream_with(
+        &self,
+        youtube_dl: impl AsRef<Path>,
+    ) -> Result<Option<AudioStream>, anyhow::Error> {
+        let output = YoutubeDl::new(&self.id).youtube_dl_path(youtube_dl).run()?;
+        match output {
             YoutubeDlOutput::SingleVideo(video) => {
-                video
+                let format = video
                     .formats
                     .into_iter()
                     .flatten()
-                    .find(|f| f.acodec.as_deref() == Some("opus"))?
-                    .url
+                    .find(|f| f.acodec.as_deref() == Some("opus"));
+                Ok(format.and_then(|format| {
+                    Some(AudioStream {
+                        url: format.url?,
+                        format_id: format.format_id?,
+                    })
+                }))
             }
-            _ => None,
+            _ => Ok(None),
         }
     }
 }
@@ -0,0 +1,95 @@
+/// The canonical location of kasetophono.com
+pub const BASE_URL: &str = "https://www.kasetophono.com";
+
+/// The kasetophono logo, as shown in the header of the website
+pub const LOGO_URL: &str = "https://3.bp.blogspot.com/-xTPGTrjKbcc/WbGOKSAWMQI/AAAAAAAAPQM/UY9fma6zC9kpAWKK8Vd1xbJhKVxiDHh2wCK4BGAYYCw/s600/kasetophono.png";
+
+/// The hosts that kasetophono.com links to itself with
+const HOSTS: &[&str] = &["www.kasetophono.com", "kasetophono.com"];
+
+/// Builds the URLs of kasetophono.com, or of a mirror that serves the same content under a
+/// different base URL
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstream {
+    base_url: String,
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self::new(BASE_URL)
+    }
+}
+
+impl Upstream {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The URL of the frontpage, which contains the category menu
+    pub fn frontpage(&self) -> String {
+        format!("{}/", self.base_url)
+    }
+
+    /// The URL of a page of the JSON feed of all posts. `start_index` is 1-based.
+    pub fn feed(&self, start_index: usize, max_results: usize) -> String {
+        format!(
+            "{}/feeds/posts/default?alt=json&start-index={}&max-results={}",
+            self.base_url, start_index, max_results
+        )
+    }
+
+    /// Rewrites an absolute URL that points to kasetophono.com, as found in scraped pages, so that
+    /// it points to this upstream instead. Any other URL is returned unchanged.
+    pub fn rewrite(&self, url: &str) -> String {
+        let rest = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"));
+        if let Some(rest) = rest {
+            for host in HOSTS {
+                if let Some(path) = rest.strip_prefix(host) {
+                    if path.is_empty() || path.starts_with('/') {
+                        return format!("{}{}", self.base_url, path);
+                    }
+                }
+            }
+        }
+        url.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rewrite() {
+        let upstream = Upstream::new("http://localhost:8080/");
+
+        assert_eq!(
+            upstream.rewrite("https://www.kasetophono.com/p/blog-page_28.html"),
+            "http://localhost:8080/p/blog-page_28.html"
+        );
+        assert_eq!(
+            upstream.rewrite("http://kasetophono.com/search/label/Playlist"),
+            "http://localhost:8080/search/label/Playlist"
+        );
+        assert_eq!(
+            upstream.rewrite("https://www.kasetophono.com.evil.example/p/4.html"),
+            "https://www.kasetophono.com.evil.example/p/4.html"
+        );
+        assert_eq!(
+            upstream.rewrite("https://www.youtube.com/embed/videoseries"),
+            "https://www.youtube.com/embed/videoseries"
+        );
+        assert_eq!(
+            upstream.feed(26, 25),
+            "http://localhost:8080/feeds/posts/default?alt=json&start-index=26&max-results=25"
+        );
+    }
+}
@@ -8,7 +8,7 @@ fn main() {
     let paths = Path::new(&out_dir).join("paths.rs");
 
     let mpv = CanonicalPath::new("mpv").expect("mpv missing from PATH");
-    fs::write(&paths, format!("static MPV: &'static str = {:?};", mpv)).unwrap();
+    fs::write(&paths, format!("static MPV: &str = {:?};", mpv)).unwrap();
 
     println!("cargo:rerun-if-env-changed=PATH");
 }
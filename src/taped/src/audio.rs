@@ -1,201 +1,763 @@
-use std::str;
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 use std::str::FromStr;
-use std::process::{Command, Stdio};
-use serde::Deserialize;
-use serde::Deserializer;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use base64::Engine;
 use serde::de;
+use serde::{Deserialize, Serialize};
+use serde::{Deserializer, Serializer};
 use serde_json::Value;
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, TempDir, TempPath};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+
+use kasetophono::Cassette;
 
-use super::Cassette;
+use crate::tags::Picture;
+use crate::{config, Result};
 
-#[derive(Debug,Copy,Clone,PartialEq,Deserialize)]
+/// How many lines from the end of the ffmpeg output are included in errors
+const ERROR_LINES: usize = 5;
+
+/// The measurements of the first loudnorm pass. Values are kept as the strings ffmpeg logs them
+/// as, which also covers the `-inf` of silent input.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LoudNorm {
-    #[serde(deserialize_with = "de_fromstr")]
-    input_i: f32,
-    #[serde(deserialize_with = "de_fromstr")]
-    input_tp: f32,
-    #[serde(deserialize_with = "de_fromstr")]
-    input_lra: f32,
-    #[serde(deserialize_with = "de_fromstr")]
-    input_thresh: f32,
-    #[serde(deserialize_with = "de_fromstr")]
-    target_offset: f32,
-}
-
-fn de_fromstr<'de, D: Deserializer<'de>, T: FromStr>(deserializer: D) -> Result<T, D::Error>  {
+    /// The integrated loudness in LUFS
+    #[serde(serialize_with = "ser_tostring", deserialize_with = "de_fromstr")]
+    pub input_i: f32,
+    /// The true peak in dBTP
+    #[serde(serialize_with = "ser_tostring", deserialize_with = "de_fromstr")]
+    pub input_tp: f32,
+    /// The loudness range in LU
+    #[serde(serialize_with = "ser_tostring", deserialize_with = "de_fromstr")]
+    pub input_lra: f32,
+    #[serde(serialize_with = "ser_tostring", deserialize_with = "de_fromstr")]
+    pub input_thresh: f32,
+    /// The gain the second pass adds to land on the target
+    #[serde(serialize_with = "ser_tostring", deserialize_with = "de_fromstr")]
+    pub target_offset: f32,
+}
+
+fn ser_tostring<S: Serializer, T: Display>(
+    value: &T,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn de_fromstr<'de, D: Deserializer<'de>, T: FromStr>(
+    deserializer: D,
+) -> std::result::Result<T, D::Error> {
     let value = Value::deserialize(deserializer)?;
-    let s = value.as_str().ok_or(de::Error::custom("invalid type"))?;
+    let s = value
+        .as_str()
+        .ok_or_else(|| de::Error::custom("invalid type"))?;
     s.parse().or(Err(de::Error::custom("invalid value")))
 }
 
-pub fn measure_loudness(path: &str) -> LoudNorm {
-    let output = Command::new("ffmpeg")
-        .args(&[
-            "-nostdin", "-nostats", "-y",
-            "-i", path,
-            "-filter_complex", "[0:0]loudnorm=i=-23.0:lra=7.0:tp=-2.0:offset=0.0:print_format=json",
-            "-vn", "-sn",
-            "-f", "null",
-            "/dev/null",
-        ])
-        .stderr(Stdio::piped())
-        .output()
-        .expect("Failed to run ffmpeg");
-
-    let mut stderr = str::from_utf8(&output.stderr).expect("Invalid utf8 in ffmpeg output").lines();
-
-    while let Some(line) = stderr.next() {
-        if line.contains("Parsed_loudnorm") {
-            break;
-        }
-    }
-
-    let result = stderr.collect::<String>();
-
-    serde_json::from_str(&result).expect("Invalid ffmpeg json")
-}
-
-pub fn correct_loudness(input: &str, output: &str, l: LoudNorm) {
-    // values taken from ffmpeg-normalize with default arguments
-    let filter = format!("[0:0]loudnorm=i=-23.0:\
-                          lra=7.0:\
-                          tp=-2.0:\
-                          offset={}:\
-                          measured_i={}:\
-                          measured_lra={}:\
-                          measured_tp={}:\
-                          measured_thresh={}:\
-                          linear=true:\
-                          print_format=json[norm0]", l.target_offset, l.input_i, l.input_lra, l.input_tp, l.input_thresh);
-
-
-    std::fs::create_dir_all(".ffmpeg-workdir").unwrap();
-    let tmp_output = NamedTempFile::new_in(".ffmpeg-workdir").unwrap().into_temp_path();
-
-    let status = Command::new("ffmpeg")
-        .args(&[
-            "-nostdin", "-nostats", "-y",
-            "-i", input,
-            "-filter_complex", &filter,
-            "-map_metadata", "0",
-            "-map_metadata:s:a:0", "0:s:a:0",
-            "-map_chapters", "0",
-            "-map", "[norm0]",
-            "-c:a", "libmp3lame",
-            "-q:a", "2",
-            "-vn", "-sn",
-            "-f", "mp3",
-            tmp_output.to_str().unwrap()
-        ])
-        .stderr(Stdio::null())
-        .status()
-        .expect("Failed to run ffmpeg");
-
-    if !status.success() {
-        panic!();
-    }
-
-    tmp_output.persist(output).unwrap();
-}
-
-pub fn add_cassette_metadata(input: &str, output: &str, cassette: &Cassette, track_n: u8, track_total: u8, album_art_path: &str) {
-    let date = &cassette.created_at[..10];
-
-    let album_metadata = format!("album={} | {}", cassette.name, date[..7].replace("-", "/"));
-    let track_metadata = format!("track={}/{}", track_n, track_total);
-    let creation_time_metadata = format!("creation_time={}", date);
-    let date_metadata = format!("date={}", date);
-
-    std::fs::create_dir_all(".ffmpeg-workdir").unwrap();
-    let tmp_output = NamedTempFile::new_in(".ffmpeg-workdir").unwrap().into_temp_path();
-
-    let status = Command::new("ffmpeg")
-        .args(&[
-            "-nostdin", "-nostats", "-y",
-            "-i", input,
-            "-i", album_art_path,
-            "-map", "0:0",
-            "-map", "1:0",
-            "-c", "copy",
-            "-c:v", "png",
-            "-id3v2_version", "3",
-            "-metadata:s:v", "title=Album cover",
-            "-metadata:s:v", "comment=Cover (front)",
-            "-metadata", &album_metadata,
-            "-metadata", &track_metadata,
-            "-metadata", &creation_time_metadata,
-            "-metadata", &date_metadata,
-            "-f", "mp3",
-            tmp_output.to_str().unwrap()
-        ])
-        .stderr(Stdio::null())
-        .status()
-        .expect("Failed to run ffmpeg");
-
-    if !status.success() {
-        panic!();
-    }
-
-    tmp_output.persist(output).unwrap();
+/// The loudness audio is normalised to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "preset", rename_all = "snake_case")]
+pub enum Profile {
+    /// EBU R128, -23 LUFS, as used by broadcasters
+    #[default]
+    Broadcast,
+    /// -14 LUFS, about what streaming services play music at
+    Streaming,
+    Custom {
+        /// The integrated loudness in LUFS, from -70 to -5
+        integrated: f32,
+        /// The loudness range in LU, from 1 to 50
+        range: f32,
+        /// The maximum true peak in dBTP, from -9 to 0
+        true_peak: f32,
+    },
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Profile {
+    /// Checks that the targets of a custom profile are within what loudnorm accepts
+    pub fn validate(self) -> Result<()> {
+        if let Profile::Custom {
+            integrated,
+            range,
+            true_peak,
+        } = self
+        {
+            if !(-70.0..=-5.0).contains(&integrated) {
+                bail!("integrated loudness must be between -70 and -5 LUFS");
+            }
+            if !(1.0..=50.0).contains(&range) {
+                bail!("loudness range must be between 1 and 50 LU");
+            }
+            if !(-9.0..=0.0).contains(&true_peak) {
+                bail!("true peak must be between -9 and 0 dBTP");
+            }
+        }
+        Ok(())
+    }
+
+    /// The integrated loudness, loudness range and true peak targets
+    fn targets(self) -> (f32, f32, f32) {
+        // The broadcast values are the ones ffmpeg-normalize uses by default
+        match self {
+            Profile::Broadcast => (-23.0, 7.0, -2.0),
+            Profile::Streaming => (-14.0, 11.0, -1.0),
+            Profile::Custom {
+                integrated,
+                range,
+                true_peak,
+            } => (integrated, range, true_peak),
+        }
+    }
+
+    /// The loudnorm filter with the targets of the profile
+    pub fn filter(self) -> String {
+        let (integrated, range, true_peak) = self.targets();
+        format!(
+            "loudnorm=i={:.1}:lra={:.1}:tp={:.1}",
+            integrated, range, true_peak
+        )
+    }
+
+    /// The gain in dB that brings audio of the measured loudness to the target, ReplayGain style:
+    /// the gain is the same for the whole track and never pushes its peak above the target.
+    pub fn gain(self, loudness: &LoudNorm) -> f32 {
+        let (integrated, _, true_peak) = self.targets();
+        let gain = (integrated - loudness.input_i).min(true_peak - loudness.input_tp);
+        // Silence measures as -inf and is best left alone
+        match gain.is_finite() {
+            true => gain,
+            false => 0.0,
+        }
+    }
+}
+
+/// The codecs tracks can be encoded with, each in its usual container
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// MP3 with ID3v2.4 tags
+    #[default]
+    Mp3,
+    /// Opus in Ogg
+    Opus,
+    /// AAC in M4A
+    Aac,
+    Flac,
+}
+
+impl Codec {
+    const ALL: [Codec; 4] = [Codec::Mp3, Codec::Opus, Codec::Aac, Codec::Flac];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Mp3 => "mp3",
+            Codec::Opus => "opus",
+            Codec::Aac => "m4a",
+            Codec::Flac => "flac",
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?;
+        Self::ALL
+            .into_iter()
+            .find(|codec| extension == codec.extension())
+    }
+
+    /// The ffmpeg muxer for the container
+    fn format(self) -> &'static str {
+        match self {
+            Codec::Mp3 => "mp3",
+            Codec::Opus => "opus",
+            // The iPod flavour of MP4 is what M4A files are
+            Codec::Aac => "ipod",
+            Codec::Flac => "flac",
+        }
+    }
+
+    fn encoder(self) -> &'static [&'static str] {
+        match self {
+            Codec::Mp3 => &["-c:a", "libmp3lame", "-q:a", "2"],
+            Codec::Opus => &["-c:a", "libopus", "-b:a", "160k"],
+            Codec::Aac => &["-c:a", "aac", "-b:a", "256k"],
+            Codec::Flac => &["-c:a", "flac"],
+        }
+    }
+}
+
+/// The formats a whole cassette can be exported in as a single file with a chapter per song
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TapeFormat {
+    /// AAC in an audiobook flavoured MP4, which podcast apps and car stereos handle best
+    M4b,
+    /// FLAC in Matroska, so that the tracks aren't encoded lossily a second time
+    Mka,
+    /// Opus in Ogg
+    Opus,
+    /// Plain FLAC, e.g. for recording to a real tape
+    Flac,
+}
+
+impl TapeFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            TapeFormat::M4b => "m4b",
+            TapeFormat::Mka => "mka",
+            TapeFormat::Opus => "opus",
+            TapeFormat::Flac => "flac",
+        }
+    }
+
+    /// The ffmpeg muxer for the container
+    fn format(self) -> &'static str {
+        match self {
+            TapeFormat::M4b => "ipod",
+            TapeFormat::Mka => "matroska",
+            TapeFormat::Opus => "opus",
+            TapeFormat::Flac => "flac",
+        }
+    }
+
+    fn codec(self) -> Codec {
+        match self {
+            TapeFormat::M4b => Codec::Aac,
+            TapeFormat::Mka => Codec::Flac,
+            TapeFormat::Opus => Codec::Opus,
+            TapeFormat::Flac => Codec::Flac,
+        }
+    }
+}
+
+/// A cassette, or a side of it, to be rendered as a single file
+pub struct Tape<'a> {
+    pub title: String,
+    pub chapters: Vec<Chapter<'a>>,
+    /// The silence before the first song, like the leader of a real tape
+    pub leader: Duration,
+    pub format: TapeFormat,
+}
+
+/// A song of a tape
+#[derive(Clone, Copy)]
+pub struct Chapter<'a> {
+    /// The track of the song in the library
+    pub path: &'a Path,
+    /// The 1-based track number of the song, which skips the songs that aren't in the library
+    pub number: usize,
+    pub title: &'a str,
+    pub duration: Duration,
+}
+
+/// How tracks are normalised and encoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Encoding {
+    #[serde(default)]
+    pub profile: Profile,
+    #[serde(default)]
+    pub codec: Codec,
+}
+
+/// Runs ffmpeg to analyse, normalise and tag audio files. Output is written to the work directory
+/// first and only moved in place once ffmpeg succeeded.
+#[derive(Clone, Debug)]
+pub struct Ffmpeg {
+    program: PathBuf,
+    work_dir: PathBuf,
+}
 
-    // #[test]
-    // fn measure_audio() {
-    //     let mut input = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    //     input.push("resources/test-audio.mp3");
-    //     let input = input.to_str().expect("Invalid path");
+impl Ffmpeg {
+    pub fn new(config: &config::Audio, work_dir: PathBuf) -> Self {
+        Self {
+            program: config.ffmpeg.clone(),
+            work_dir,
+        }
+    }
+
+    /// Creates a scratch directory in the work directory, which is removed when dropped
+    pub fn tempdir(&self) -> Result<TempDir> {
+        std::fs::create_dir_all(&self.work_dir)?;
+        Ok(tempfile::Builder::new()
+            .prefix("taped-")
+            .tempdir_in(&self.work_dir)?)
+    }
+
+    /// Measures the loudness of a file, or of a stream given by its URL, in a first normalisation
+    /// pass. `progress` is called with the percentage done if the duration of the input is known.
+    pub async fn measure_loudness(
+        &self,
+        input: &Path,
+        profile: Profile,
+        duration: Option<Duration>,
+        progress: impl FnMut(f32),
+    ) -> Result<LoudNorm> {
+        let filter = format!("{}:offset=0.0:print_format=json", profile.filter());
+        let mut command = self.command();
+        command.arg("-i").arg(input).args([
+            "-map",
+            "0:a:0",
+            "-filter:a",
+            &filter,
+            "-vn",
+            "-sn",
+            "-f",
+            "null",
+            "-",
+        ]);
+        let output = self.run(command, duration, progress).await?;
+        parse_loudnorm(&output)
+    }
+
+    /// Normalises the loudness of a file using the measurements of the first pass and encodes it
+    /// with the given codec
+    pub async fn correct_loudness(
+        &self,
+        input: &Path,
+        output: &Path,
+        l: LoudNorm,
+        encoding: Encoding,
+        duration: Option<Duration>,
+        progress: impl FnMut(f32),
+    ) -> Result<()> {
+        let filter = format!(
+            "[0:a:0]{}:\
+             offset={}:\
+             measured_i={}:\
+             measured_lra={}:\
+             measured_tp={}:\
+             measured_thresh={}:\
+             linear=true:\
+             print_format=json[norm0]",
+            encoding.profile.filter(),
+            l.target_offset,
+            l.input_i,
+            l.input_lra,
+            l.input_tp,
+            l.input_thresh
+        );
+
+        let tmp_output = self.temp()?;
+        let mut command = self.command();
+        command
+            .arg("-i")
+            .arg(input)
+            .args([
+                "-filter_complex",
+                &filter,
+                "-map_metadata",
+                "0",
+                "-map_metadata:s:a:0",
+                "0:s:a:0",
+                "-map_chapters",
+                "0",
+                "-map",
+                "[norm0]",
+                // loudnorm upsamples to 192 kHz, which no listener needs
+                "-ar",
+                "48000",
+            ])
+            .args(encoding.codec.encoder())
+            .args(["-vn", "-sn", "-f", encoding.codec.format()])
+            .arg(&tmp_output);
+        self.run(command, duration, progress).await?;
+        persist(tmp_output, output).await
+    }
+
+    /// Joins tracks of a cassette into a single file with a chapter per song, tagged with the
+    /// cassette, its description and cover
+    pub async fn render_tape(
+        &self,
+        tape: &Tape<'_>,
+        output: &Path,
+        cassette: &Cassette,
+        album_art_path: Option<&Path>,
+        progress: impl FnMut(f32),
+    ) -> Result<()> {
+        if tape.chapters.is_empty() {
+            bail!("there are no tracks to put on the tape");
+        }
+        // The first chapter starts with the leader so that the whole tape is covered
+        let mut marks = vec![];
+        let mut position = Duration::ZERO;
+        for (i, chapter) in tape.chapters.iter().enumerate() {
+            let leader = if i == 0 { tape.leader } else { Duration::ZERO };
+            let end = position + leader + chapter.duration;
+            marks.push((position, end, chapter.title));
+            position = end;
+        }
+
+        let format = tape.format;
+        let (album, date) = album(cassette)?;
+        let mut tags = vec![
+            ("title", tape.title.as_str()),
+            ("album", &album),
+            ("date", date),
+            ("comment", &cassette.url),
+        ];
+        if let Some(description) = &cassette.description {
+            tags.push(("description", description));
+        }
+        // Ogg can't carry attached pictures, so the cover goes in a comment instead
+        let block = match (album_art_path, format) {
+            (Some(album_art_path), TapeFormat::Opus) => Some(picture_block(album_art_path).await?),
+            _ => None,
+        };
+        if let Some(block) = &block {
+            tags.push(("METADATA_BLOCK_PICTURE", block));
+        }
+        let metadata = self.temp()?;
+        tokio::fs::write(&metadata, ffmetadata(&tags, &marks)).await?;
+
+        let tmp_output = self.temp()?;
+        let mut command = self.command();
+        if !tape.leader.is_zero() {
+            let leader = format!("{:.3}", tape.leader.as_secs_f64());
+            command
+                .args(["-f", "lavfi", "-t", &leader, "-i"])
+                .arg("anullsrc=r=48000:cl=stereo");
+        }
+        for chapter in &tape.chapters {
+            command.arg("-i").arg(chapter.path);
+        }
+        let n = tape.chapters.len() + usize::from(!tape.leader.is_zero());
+        command.args(["-f", "ffmetadata", "-i"]).arg(&metadata);
+        let cover = album_art_path.filter(|_| block.is_none());
+        if let Some(album_art_path) = cover {
+            command.arg("-i").arg(album_art_path);
+        }
+        command.args(["-filter_complex", &concat_filter(n), "-map", "[tape]"]);
+        if cover.is_some() {
+            command.args([
+                "-map",
+                &format!("{}:0", n + 1),
+                "-c:v",
+                "copy",
+                "-disposition:v:0",
+                "attached_pic",
+            ]);
+        }
+        command
+            .args([
+                "-map_metadata",
+                &n.to_string(),
+                "-map_chapters",
+                &n.to_string(),
+            ])
+            .args(format.codec().encoder())
+            .args(["-sn", "-f", format.format()])
+            .arg(&tmp_output);
+        self.run(command, Some(position), progress).await?;
+        persist(tmp_output, output).await
+    }
+
+    /// The duration of an audio file, as ffmpeg logs it when opening the file
+    pub async fn duration(&self, input: &Path) -> Result<Duration> {
+        let mut command = self.command();
+        command
+            .arg("-i")
+            .arg(input)
+            .args(["-map", "0:a:0", "-c", "copy", "-f", "null", "-"]);
+        let log = self.run(command, None, |_| {}).await?;
+        parse_duration(&log)
+            .ok_or_else(|| anyhow!("ffmpeg didn't log the duration of {}", input.display()))
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .kill_on_drop(true)
+            .args(["-nostdin", "-hide_banner", "-nostats", "-y"])
+            .args(["-progress", "pipe:1"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
+
+    /// Runs ffmpeg to completion, reporting its progress, and returns what it logged
+    async fn run(
+        &self,
+        mut command: Command,
+        duration: Option<Duration>,
+        mut progress: impl FnMut(f32),
+    ) -> Result<String> {
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("running {}", self.program.display()))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+
+        // Both pipes are drained at the same time so that ffmpeg never blocks on a full one
+        let read_progress = async {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Some(line) = lines.next_line().await? {
+                if let (Some(done), Some(duration)) = (parse_progress(&line), duration) {
+                    progress(percentage(done, duration));
+                }
+            }
+            Ok::<_, io::Error>(())
+        };
+        let read_log = async {
+            let mut log = vec![];
+            stderr.read_to_end(&mut log).await?;
+            Ok::<_, io::Error>(String::from_utf8_lossy(&log).into_owned())
+        };
+        let ((), log) = tokio::try_join!(read_progress, read_log)?;
+
+        let status = child.wait().await?;
+        if !status.success() {
+            let lines: Vec<&str> = log.lines().collect();
+            let tail = lines[lines.len().saturating_sub(ERROR_LINES)..].join("\n");
+            bail!(
+                "{} failed with {}: {}",
+                self.program.display(),
+                status,
+                tail
+            );
+        }
+        Ok(log)
+    }
+
+    fn temp(&self) -> Result<TempPath> {
+        std::fs::create_dir_all(&self.work_dir)?;
+        Ok(NamedTempFile::new_in(&self.work_dir)?.into_temp_path())
+    }
+}
+
+/// Moves a finished file in place, copying it if the work directory is on another filesystem
+async fn persist(tmp_output: TempPath, output: &Path) -> Result<()> {
+    let tmp_output = match tmp_output.persist(output) {
+        Ok(()) => return Ok(()),
+        Err(err) => err.path,
+    };
+    let dir = output.parent().unwrap_or_else(|| Path::new("."));
+    let copy = NamedTempFile::new_in(dir)?.into_temp_path();
+    tokio::fs::copy(&tmp_output, &copy).await?;
+    copy.persist(output)?;
+    Ok(())
+}
 
-    //     let loudnorm = measure_loudness(input);
+/// Builds a FLAC picture block for the cover, as Ogg files carry it in a METADATA_BLOCK_PICTURE
+/// comment
+async fn picture_block(path: &Path) -> Result<String> {
+    let block = Picture::load(path).await?.flac_block();
+    Ok(base64::engine::general_purpose::STANDARD.encode(block))
+}
 
-    //     let expected = LoudNorm{
-    //         input_i: -14.01,
-    //         input_tp: -0.21,
-    //         input_lra: 1.1,
-    //         input_thresh: -24.03,
-    //         target_offset: 0.35
-    //     };
+/// The album tracks of a cassette are tagged with and the date of the cassette
+pub fn album(cassette: &Cassette) -> Result<(String, &str)> {
+    let date = cassette
+        .created_at
+        .get(..10)
+        .ok_or_else(|| anyhow!("invalid creation date {:?}", cassette.created_at))?;
+    let album = format!("{} | {}", cassette.name, date[..7].replace('-', "/"));
+    Ok((album, date))
+}
+
+/// The filter that joins the first audio stream of `n` inputs into `[tape]`. Tracks are brought
+/// to the same sample rate and channels first, since they may have been encoded differently.
+fn concat_filter(n: usize) -> String {
+    let mut filter = String::new();
+    for i in 0..n {
+        filter += &format!(
+            "[{0}:a:0]aresample=48000,aformat=channel_layouts=stereo[a{0}];",
+            i
+        );
+    }
+    for i in 0..n {
+        filter += &format!("[a{}]", i);
+    }
+    filter + &format!("concat=n={}:v=0:a=1[tape]", n)
+}
 
-    //     assert_eq!(loudnorm, expected);
-    // }
+/// Renders tags and chapters, given by their start, end and title, in ffmpeg's metadata file
+/// format. Unlike command line arguments, these files can hold values as large as pictures.
+fn ffmetadata(tags: &[(&str, &str)], chapters: &[(Duration, Duration, &str)]) -> String {
+    let escape = |text: &str| {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    };
+    let mut metadata = String::from(";FFMETADATA1\n");
+    for (key, value) in tags {
+        metadata += &format!("{}={}\n", escape(key), escape(value));
+    }
+    for (start, end, title) in chapters {
+        metadata += &format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            start.as_millis(),
+            end.as_millis(),
+            escape(title)
+        );
+    }
+    metadata
+}
 
-    // #[test]
-    // fn correct_audio() {
-    //     let base = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// Extracts how far ffmpeg got from a line of its `-progress` output
+fn parse_progress(line: &str) -> Option<Duration> {
+    let micros = line.strip_prefix("out_time_us=")?;
+    Some(Duration::from_micros(micros.trim().parse().ok()?))
+}
 
-    //     let mut input = base.clone();
-    //     input.push("resources/test-audio.mp3");
-    //     let input = input.to_str().expect("Invalid path");
+/// Finds the duration of the input in the log of ffmpeg, e.g. `Duration: 00:03:20.05, start: ...`
+fn parse_duration(log: &str) -> Option<Duration> {
+    let start = log.find("Duration: ")? + "Duration: ".len();
+    let duration = log[start..].split(',').next()?;
+    let mut seconds = 0.0;
+    for part in duration.split(':') {
+        seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(Duration::from_secs_f64(seconds))
+}
 
-    //     let mut output = base.clone();
-    //     output.push("resources/test-correct_audio.mp3");
-    //     let output = output.to_str().expect("Invalid path");
+fn percentage(done: Duration, total: Duration) -> f32 {
+    if total.is_zero() {
+        return 0.0;
+    }
+    (done.as_secs_f32() / total.as_secs_f32() * 100.0).clamp(0.0, 100.0)
+}
 
-    //     let loudnorm = measure_loudness(input);
+/// Finds the measurements of the loudnorm filter, which it logs as the last JSON object
+fn parse_loudnorm(log: &str) -> Result<LoudNorm> {
+    let missing = || anyhow!("ffmpeg didn't log any loudness measurements");
+    let start = log.rfind("\n{").ok_or_else(missing)? + 1;
+    let end = start + log[start..].find("\n}").ok_or_else(missing)? + 2;
+    serde_json::from_str(&log[start..end]).context("parsing loudness measurements")
+}
 
-    //     correct_loudness(input, output, loudnorm);
-    // }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // #[test]
-    // fn correct_metadata() {
-    //     let base = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    #[test]
+    fn progress() {
+        assert_eq!(
+            parse_progress("out_time_us=90000000"),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(parse_progress("out_time_us=N/A"), None);
+        assert_eq!(parse_progress("progress=continue"), None);
+        let total = Duration::from_secs(180);
+        assert_eq!(percentage(Duration::from_secs(90), total), 50.0);
+        assert_eq!(percentage(Duration::from_secs(200), total), 100.0);
+    }
 
-    //     let mut input = base.clone();
-    //     input.push("resources/test-audio.mp3");
-    //     let input = input.to_str().expect("Invalid path");
+    #[test]
+    fn profiles() {
+        assert_eq!(
+            Profile::Streaming.filter(),
+            "loudnorm=i=-14.0:lra=11.0:tp=-1.0"
+        );
+        let custom = |integrated| Profile::Custom {
+            integrated,
+            range: 7.0,
+            true_peak: -2.0,
+        };
+        assert!(custom(-16.0).validate().is_ok());
+        let loudness = |input_i, input_tp| LoudNorm {
+            input_i,
+            input_tp,
+            input_lra: 5.0,
+            input_thresh: -30.0,
+            target_offset: 0.0,
+        };
+        assert_eq!(Profile::Streaming.gain(&loudness(-9.0, -0.5)), -5.0);
+        // Quiet tracks are only made as loud as their peaks allow
+        assert_eq!(Profile::Streaming.gain(&loudness(-20.0, -3.0)), 2.0);
+        let silence = loudness(f32::NEG_INFINITY, f32::NEG_INFINITY);
+        assert_eq!(Profile::Streaming.gain(&silence), 0.0);
+        assert!(custom(-3.0).validate().is_err());
+        assert_eq!(
+            Codec::from_path(Path::new("01 - Intro.m4a")),
+            Some(Codec::Aac)
+        );
+    }
 
-    //     let mut output = base.clone();
-    //     output.push("resources/test-correct_metadata.mp3");
-    //     let output = output.to_str().expect("Invalid path");
+    #[tokio::test]
+    async fn opus_cover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cover.png");
+        image::DynamicImage::new_rgb8(3, 2).save(&path).unwrap();
+
+        let block = picture_block(&path).await.unwrap();
+        let block = base64::engine::general_purpose::STANDARD
+            .decode(block)
+            .unwrap();
+        assert_eq!(&block[..4], &3u32.to_be_bytes());
+        assert_eq!(&block[8..17], b"image/png");
+        // Dimensions follow the empty description
+        assert_eq!(&block[21..29], &[0, 0, 0, 3, 0, 0, 0, 2]);
+
+        assert_eq!(
+            ffmetadata(&[("METADATA_BLOCK_PICTURE", "AAA=;#")], &[]),
+            ";FFMETADATA1\nMETADATA_BLOCK_PICTURE=AAA\\=\\;\\#\n"
+        );
+    }
 
-    //     let mut img_path = base.clone();
-    //     img_path.push("resources/album-art.gif");
-    //     let img_path = img_path.to_str().expect("Invalid path");
+    #[test]
+    fn tape() {
+        let chapters = [
+            (Duration::ZERO, Duration::from_millis(200_050), "Intro"),
+            (
+                Duration::from_millis(200_050),
+                Duration::from_secs(400),
+                "Outro",
+            ),
+        ];
+        assert_eq!(
+            ffmetadata(&[("title", "Nero")], &chapters),
+            ";FFMETADATA1\ntitle=Nero\n\
+             [CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=200050\ntitle=Intro\n\
+             [CHAPTER]\nTIMEBASE=1/1000\nSTART=200050\nEND=400000\ntitle=Outro\n"
+        );
+        assert_eq!(
+            concat_filter(2),
+            "[0:a:0]aresample=48000,aformat=channel_layouts=stereo[a0];\
+             [1:a:0]aresample=48000,aformat=channel_layouts=stereo[a1];\
+             [a0][a1]concat=n=2:v=0:a=1[tape]"
+        );
+        let log = "Input #0, mp3, from '01 - Intro.mp3':\n  Duration: 00:03:20.05, start: 0.025057, bitrate: 245 kb/s\n";
+        assert_eq!(parse_duration(log), Some(Duration::from_millis(200_050)));
+        assert_eq!(parse_duration("  Duration: N/A, bitrate: N/A"), None);
+    }
 
-    //     add_cassette_metadata(input, output, "My album", 3, 10, img_path);
-    // }
+    #[test]
+    fn loudnorm() {
+        let log = r#"Input #0, matroska,webm, from 'source.webm':
+  Duration: 00:03:20.00, start: -0.007000, bitrate: 129 kb/s
+[Parsed_loudnorm_0 @ 0x5581b7c0]
+{
+	"input_i" : "-14.01",
+	"input_tp" : "-0.21",
+	"input_lra" : "1.10",
+	"input_thresh" : "-24.03",
+	"output_i" : "-22.60",
+	"output_tp" : "-8.48",
+	"output_lra" : "1.00",
+	"output_thresh" : "-32.61",
+	"normalization_type" : "dynamic",
+	"target_offset" : "-0.40"
+}
+"#;
+        let expected = LoudNorm {
+            input_i: -14.01,
+            input_tp: -0.21,
+            input_lra: 1.1,
+            input_thresh: -24.03,
+            target_offset: -0.4,
+        };
+        assert_eq!(parse_loudnorm(log).unwrap(), expected);
+        assert!(parse_loudnorm("Invalid data found when processing input").is_err());
+    }
 }
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use axum::http::header::{self, HeaderMap, HeaderValue};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+
+use crate::catalog::Catalog;
+use crate::Result;
+
+/// How many responses are cached per catalog generation before the cache starts over
+const MAX_ENTRIES: usize = 64;
+
+/// Caches the serialized responses of the catalog endpoints. Every response is only valid for the
+/// catalog generation it was rendered from and the cache empties itself when the generation
+/// changes.
+#[derive(Default)]
+pub struct ResponseCache {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    generation: u64,
+    entries: HashMap<String, Arc<Body>>,
+}
+
+/// A JSON response body, along with its gzip compressed version
+pub struct Body {
+    json: Bytes,
+    gzip: Bytes,
+}
+
+impl Body {
+    fn render<T: Serialize>(value: &T) -> Result<Self> {
+        let json = serde_json::to_vec(value)?;
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&json)?;
+        let gzip = encoder.finish()?;
+        Ok(Self {
+            json: json.into(),
+            gzip: gzip.into(),
+        })
+    }
+}
+
+impl ResponseCache {
+    /// Returns the cached body for `key` in the given catalog generation, rendering and caching it
+    /// if it isn't cached yet
+    pub fn get<T: Serialize>(
+        &self,
+        generation: u64,
+        key: &str,
+        value: impl FnOnce() -> T,
+    ) -> Result<Arc<Body>> {
+        {
+            let inner = self.inner.lock().unwrap();
+            if inner.generation == generation {
+                if let Some(body) = inner.entries.get(key) {
+                    return Ok(body.clone());
+                }
+            }
+        }
+
+        // Render without holding the lock so that other requests aren't held up
+        let body = Arc::new(Body::render(&value())?);
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation || inner.entries.len() >= MAX_ENTRIES {
+            inner.generation = generation;
+            inner.entries.clear();
+        }
+        inner.entries.insert(key.to_string(), body.clone());
+        Ok(body)
+    }
+}
+
+/// The entity tag of the responses rendered from a catalog. It includes the fetch time so that
+/// generations restarting from scratch, e.g. after the snapshot was deleted, don't reuse tags. It
+/// is weak since the same entity is served both compressed and uncompressed.
+pub fn etag(catalog: &Catalog) -> String {
+    let fetched_at = catalog.fetched_at.map_or(0, |at| at.timestamp_millis());
+    format!("W/\"{}-{}\"", catalog.generation, fetched_at)
+}
+
+/// Whether the client already has the response with the given entity tag
+pub fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag))
+}
+
+/// Builds the response for a request to a catalog endpoint, answering with 304 Not Modified if
+/// the client already has the current version
+pub fn respond(
+    headers: &HeaderMap,
+    etag: &str,
+    body: impl FnOnce() -> Result<Arc<Body>>,
+) -> Result<Response> {
+    let etag = HeaderValue::from_str(etag)?;
+    let vary = HeaderValue::from_static("accept-encoding");
+    if not_modified(headers, etag.to_str()?) {
+        let headers = [(header::ETAG, etag), (header::VARY, vary)];
+        return Ok((StatusCode::NOT_MODIFIED, axum::response::Headers(headers)).into_response());
+    }
+
+    let body = body()?;
+    let mut response = if accepts_gzip(headers) {
+        let mut response = body.gzip.clone().into_response();
+        let encoding = HeaderValue::from_static("gzip");
+        response
+            .headers_mut()
+            .insert(header::CONTENT_ENCODING, encoding);
+        response
+    } else {
+        body.json.clone().into_response()
+    };
+    let headers = response.headers_mut();
+    let json = HeaderValue::from_static("application/json");
+    headers.insert(header::CONTENT_TYPE, json);
+    headers.insert(header::ETAG, etag);
+    headers.insert(header::VARY, vary);
+    Ok(response)
+}
+
+/// Whether the client accepts gzip compressed responses. A quality of zero means it doesn't.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|encoding| {
+            let mut parts = encoding.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            name.eq_ignore_ascii_case("gzip") && quality > 0.0
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conditional() {
+        let mut catalog = Catalog {
+            generation: 3,
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        assert!(!not_modified(&headers, &etag(&catalog)));
+
+        headers.insert(header::IF_NONE_MATCH, "\"2-0\", W/\"3-0\"".parse().unwrap());
+        assert!(not_modified(&headers, &etag(&catalog)));
+        catalog.generation = 4;
+        assert!(!not_modified(&headers, &etag(&catalog)));
+
+        headers.insert(header::IF_NONE_MATCH, "*".parse().unwrap());
+        assert!(not_modified(&headers, &etag(&catalog)));
+    }
+
+    #[test]
+    fn encodings() {
+        let accepts = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static(value));
+            accepts_gzip(&headers)
+        };
+        assert!(accepts("gzip, deflate, br"));
+        assert!(accepts("br;q=1.0, GZIP;q=0.5"));
+        assert!(!accepts("gzip;q=0"));
+        assert!(!accepts("gzip; q=0.000, identity"));
+        assert!(!accepts("identity"));
+        assert!(!accepts_gzip(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn cached_per_generation() {
+        let cache = ResponseCache::default();
+        let mut renders = 0;
+        let mut render = |value: u32| {
+            renders += 1;
+            value
+        };
+
+        let first = cache.get(1, "key", || render(1)).unwrap();
+        let again = cache.get(1, "key", || render(2)).unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        let next = cache.get(2, "key", || render(3)).unwrap();
+        assert_eq!(&next.json[..], b"3");
+        assert_eq!(renders, 2);
+    }
+}
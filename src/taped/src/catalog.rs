@@ -0,0 +1,377 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use kasetophono::Cassette;
+
+use crate::{persist, Result};
+
+/// The version of the snapshot format. It must be bumped whenever the layout of `Catalog`, or of
+/// any of the types it contains, changes in an incompatible way.
+const VERSION: u32 = 1;
+
+pub type Cassettes = HashMap<Uuid, Cassette>;
+
+/// The names of the subcategories of every category, by category name
+pub type Categories = BTreeMap<String, Vec<String>>;
+
+/// Everything taped knows about kasetophono.com
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Catalog {
+    pub cassettes: Arc<Cassettes>,
+    #[serde(default)]
+    pub categories: Categories,
+    /// When the catalog was fetched from upstream. It is `None` until the first refresh.
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// Incremented every time the catalog is refreshed. Responses rendered from the catalog are
+    /// cached per generation.
+    #[serde(default)]
+    pub generation: u64,
+    /// Whether some upstream pages could not be fetched during the last refresh, in which case
+    /// the cassettes it did fetch were merged with the previous catalog
+    #[serde(default)]
+    pub partial: bool,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<C> {
+    version: u32,
+    catalog: C,
+}
+
+impl Catalog {
+    pub fn new(cassettes: Cassettes, categories: Categories) -> Self {
+        Self {
+            cassettes: Arc::new(cassettes),
+            categories,
+            fetched_at: Some(Utc::now()),
+            generation: 0,
+            partial: false,
+        }
+    }
+
+    /// Builds the catalog of a partial refresh, in which the fetched cassettes and categories
+    /// replace their previous versions and the ones that weren't fetched are kept from this
+    /// catalog. The subcategories of categories that weren't fetched are unknown to the fetched
+    /// cassettes, which keep the ones they previously had instead.
+    pub fn merge(&self, mut cassettes: Cassettes, categories: Categories) -> Self {
+        let missing: Vec<&String> = self
+            .categories
+            .iter()
+            .filter(|(category, _)| !categories.contains_key(*category))
+            .flat_map(|(_, subcategories)| subcategories)
+            .collect();
+        for cassette in cassettes.values_mut() {
+            let previous = match self.cassettes.get(&cassette.uuid) {
+                Some(previous) => previous,
+                None => continue,
+            };
+            let kept = previous
+                .subcategories
+                .iter()
+                .filter(|sub| missing.contains(&&sub.name))
+                .filter(|sub| !cassette.subcategories.contains(sub));
+            cassette
+                .subcategories
+                .extend(kept.cloned().collect::<Vec<_>>());
+        }
+
+        let mut merged = (*self.cassettes).clone();
+        merged.extend(cassettes);
+        let mut merged_categories = self.categories.clone();
+        merged_categories.extend(categories);
+        Self {
+            partial: true,
+            ..Self::new(merged, merged_categories)
+        }
+    }
+
+    /// Lists the cassettes that match a query, one page at a time
+    pub fn query(&self, query: &Query) -> Page<'_> {
+        let category = match &query.category {
+            Some(name) => match self.categories.get(name) {
+                Some(subcategories) => Some(subcategories.as_slice()),
+                None => Some(&[][..]),
+            },
+            None => None,
+        };
+        let text = query.q.as_ref().map(|q| q.to_lowercase());
+
+        let mut cassettes: Vec<&Cassette> = self
+            .cassettes
+            .values()
+            .filter(|c| match &query.label {
+                Some(label) => c.labels.iter().any(|l| l.eq_ignore_ascii_case(label)),
+                None => true,
+            })
+            .filter(|c| match &query.subcategory {
+                Some(name) => c
+                    .subcategories
+                    .iter()
+                    .any(|s| s.name.eq_ignore_ascii_case(name)),
+                None => true,
+            })
+            .filter(|c| match category {
+                Some(names) => c.subcategories.iter().any(|s| names.contains(&s.name)),
+                None => true,
+            })
+            .filter(|c| match query.year {
+                Some(year) => date_part(c, 0..4) == Some(year),
+                None => true,
+            })
+            .filter(|c| match query.month {
+                Some(month) => date_part(c, 5..7) == Some(month),
+                None => true,
+            })
+            .filter(|c| match &text {
+                Some(text) => matches_text(c, text),
+                None => true,
+            })
+            .collect();
+
+        // The uuid breaks ties so that pages don't overlap when the sort key is the same
+        match query.sort {
+            Sort::Newest => {
+                cassettes.sort_by(|a, b| (&b.created_at, b.uuid).cmp(&(&a.created_at, a.uuid)))
+            }
+            Sort::Name => cassettes.sort_by_cached_key(|c| (c.name.to_lowercase(), c.uuid)),
+            // Cassettes of unknown duration go last
+            Sort::Duration => cassettes.sort_by_cached_key(|c| {
+                let duration = duration(c);
+                (duration.is_none(), duration, c.uuid)
+            }),
+        }
+
+        let total = cassettes.len();
+        let start = query.cursor.unwrap_or(0).min(total);
+        let end = start.saturating_add(query.limit()).min(total);
+        Page {
+            cassettes: cassettes[start..end].to_vec(),
+            total,
+            next_cursor: Some(end).filter(|&end| end < total),
+        }
+    }
+
+    /// Loads the catalog from a snapshot file. Snapshots written by an incompatible version of
+    /// taped are ignored.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match persist::load::<Header>(path)? {
+            Some(header) if header.version == VERSION => {
+                let snapshot = persist::load::<Snapshot<Catalog>>(path)?;
+                Ok(snapshot.map(|snapshot| snapshot.catalog))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Atomically writes the catalog to a snapshot file
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let snapshot = Snapshot {
+            version: VERSION,
+            catalog: self,
+        };
+        persist::save(path, &snapshot)
+    }
+}
+
+/// How to sort a listing of cassettes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    /// Most recently published first
+    #[default]
+    Newest,
+    /// Alphabetically by name
+    Name,
+    /// Shortest first
+    Duration,
+}
+
+/// Which cassettes to list. Every filter that is set must match.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Query {
+    pub label: Option<String>,
+    pub subcategory: Option<String>,
+    pub category: Option<String>,
+    /// The year the cassette was published in
+    pub year: Option<u32>,
+    /// The month the cassette was published in, from 1 to 12
+    pub month: Option<u32>,
+    /// Text to look for in the names, labels and song titles of the cassettes
+    pub q: Option<String>,
+    pub sort: Sort,
+    /// How many cassettes to return. Defaults to `DEFAULT_LIMIT` and is clamped between 1 and
+    /// `MAX_LIMIT`, so that following `next_cursor` always gets somewhere.
+    pub limit: Option<usize>,
+    /// The `next_cursor` of the previous page
+    pub cursor: Option<usize>,
+}
+
+pub const DEFAULT_LIMIT: usize = 50;
+pub const MAX_LIMIT: usize = 500;
+
+impl Query {
+    fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+/// A page of a listing of cassettes
+#[derive(Debug, Serialize)]
+pub struct Page<'a> {
+    pub cassettes: Vec<&'a Cassette>,
+    /// How many cassettes match the query across all pages
+    pub total: usize,
+    /// The cursor of the next page, if there is one
+    pub next_cursor: Option<usize>,
+}
+
+/// Parses part of the publication date of a cassette, e.g. `0..4` for the year
+fn date_part(cassette: &Cassette, range: std::ops::Range<usize>) -> Option<u32> {
+    cassette.created_at.get(range)?.parse().ok()
+}
+
+/// The total duration of the songs of a cassette in seconds, if known
+fn duration(cassette: &Cassette) -> Option<u64> {
+    if cassette.videos.is_empty() {
+        return None;
+    }
+    cassette.videos.iter().map(|song| song.duration).sum()
+}
+
+/// Whether `text`, which must be lowercase, appears anywhere in the cassette
+fn matches_text(cassette: &Cassette, text: &str) -> bool {
+    cassette.name.to_lowercase().contains(text)
+        || cassette
+            .labels
+            .iter()
+            .any(|l| l.to_lowercase().contains(text))
+        || cassette
+            .videos
+            .iter()
+            .any(|s| s.title.to_lowercase().contains(text))
+}
+
+#[cfg(test)]
+mod test {
+    use kasetophono::{Song, Subcategory, SubcategoryKind};
+
+    use super::*;
+
+    fn cassette(name: &str, created_at: &str, label: &str, durations: &[u64]) -> Cassette {
+        Cassette {
+            uuid: Uuid::new_v5(&Uuid::NAMESPACE_URL, name.as_bytes()),
+            name: name.into(),
+            safe_name: name.into(),
+            videos: durations
+                .iter()
+                .map(|&duration| Song {
+                    id: String::new(),
+                    title: format!("{} song", name),
+                    duration: Some(duration),
+                })
+                .collect(),
+            labels: vec![label.into()],
+            subcategories: vec![Subcategory {
+                name: label.into(),
+                kind: SubcategoryKind::Label(label.into()),
+            }],
+            created_at: created_at.into(),
+            ..Default::default()
+        }
+    }
+
+    fn catalog() -> Catalog {
+        let cassettes = [
+            cassette(
+                "Summer",
+                "2021-07-01T10:00:00.000+03:00",
+                "Pop",
+                &[200, 200],
+            ),
+            cassette("Winter", "2021-12-24T10:00:00.000+02:00", "Jazz", &[100]),
+            cassette("Autumn", "2020-10-10T10:00:00.000+03:00", "Jazz", &[]),
+        ];
+        let cassettes = cassettes.into_iter().map(|c| (c.uuid, c)).collect();
+        let mut categories = Categories::new();
+        categories.insert("Moods".into(), vec!["Jazz".into()]);
+        Catalog::new(cassettes, categories)
+    }
+
+    fn names(page: &Page) -> Vec<String> {
+        page.cassettes.iter().map(|c| c.name.clone()).collect()
+    }
+
+    #[test]
+    fn filter_and_sort() {
+        let catalog = catalog();
+        let query = |query: Query| names(&catalog.query(&query));
+
+        assert_eq!(query(Query::default()), ["Winter", "Summer", "Autumn"]);
+        let jazz = Query {
+            label: Some("jazz".into()),
+            sort: Sort::Name,
+            ..Default::default()
+        };
+        assert_eq!(query(jazz), ["Autumn", "Winter"]);
+        let moods = Query {
+            category: Some("Moods".into()),
+            year: Some(2021),
+            ..Default::default()
+        };
+        assert_eq!(query(moods), ["Winter"]);
+        let july = Query {
+            month: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(query(july), ["Summer"]);
+        let text = Query {
+            q: Some("WINTER SONG".into()),
+            ..Default::default()
+        };
+        assert_eq!(query(text), ["Winter"]);
+        let by_duration = Query {
+            sort: Sort::Duration,
+            ..Default::default()
+        };
+        assert_eq!(query(by_duration), ["Winter", "Summer", "Autumn"]);
+    }
+
+    #[test]
+    fn paginate() {
+        let catalog = catalog();
+
+        let mut query = Query {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = catalog.query(&query);
+        assert_eq!(names(&first), ["Winter", "Summer"]);
+        assert_eq!(first.total, 3);
+        assert_eq!(first.next_cursor, Some(2));
+
+        query.cursor = first.next_cursor;
+        let second = catalog.query(&query);
+        assert_eq!(names(&second), ["Autumn"]);
+        assert_eq!(second.next_cursor, None);
+
+        // An empty page would have the same cursor as the next one
+        let query = Query {
+            limit: Some(0),
+            ..Default::default()
+        };
+        let page = catalog.query(&query);
+        assert_eq!(names(&page), ["Winter"]);
+        assert_eq!(page.next_cursor, Some(1));
+    }
+}
@@ -0,0 +1,377 @@
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::audio::{Codec, Encoding, Profile};
+use crate::cover::{self, Fit};
+use crate::{persist, Result};
+
+include!(concat!(env!("OUT_DIR"), "/paths.rs"));
+
+/// The kasetophono daemon
+#[derive(Debug, Parser)]
+#[clap(version)]
+pub struct Args {
+    /// The configuration file to use. Defaults to `$XDG_CONFIG_HOME/taped/config.toml` if it
+    /// exists.
+    #[clap(short, long, env = "TAPED_CONFIG")]
+    config: Option<PathBuf>,
+    /// An address to listen on for HTTP requests. Can be given multiple times.
+    #[clap(short, long)]
+    listen: Vec<SocketAddr>,
+    /// The directory taped keeps its state in
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+    /// The base URL of kasetophono.com or a mirror of it
+    #[clap(long)]
+    upstream: Option<String>,
+    /// How often to refresh the catalog, e.g. "12h"
+    #[clap(long, parse(try_from_str = humantime::parse_duration))]
+    refresh_interval: Option<Duration>,
+    /// How many upstream requests to make in parallel
+    #[clap(long)]
+    concurrency: Option<usize>,
+    /// The player executable
+    #[clap(long)]
+    player: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The addresses to listen on for HTTP requests
+    pub listen: Vec<SocketAddr>,
+    /// The directory taped keeps its state in
+    pub data_dir: PathBuf,
+    pub upstream: Upstream,
+    pub refresh: Refresh,
+    pub player: Player,
+    pub downloads: Downloads,
+    pub audio: Audio,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Upstream {
+    /// The base URL of kasetophono.com
+    pub base_url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Refresh {
+    /// How often the catalog is fetched from upstream
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// How long to wait before trying again after a failed refresh
+    #[serde(with = "humantime_serde")]
+    pub retry: Duration,
+    /// How many posts to request per feed page
+    pub page_size: usize,
+    /// How many upstream requests to make in parallel
+    pub concurrency: usize,
+    /// How long a single upstream request may take
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// The User-Agent header sent to upstream
+    pub user_agent: String,
+    /// How many times a failed upstream request is attempted in total
+    pub attempts: u32,
+    /// How long to wait before the first retry of a failed request. Doubles, with jitter, for
+    /// every following retry.
+    #[serde(with = "humantime_serde")]
+    pub backoff: Duration,
+    /// The longest to wait between two retries of a request
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+    /// Whether a refresh in which some pages could not be fetched is merged with the previous
+    /// catalog instead of being discarded
+    pub allow_partial: bool,
+}
+
+/// The program used for playback
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    Mpv,
+}
+
+/// How the player evens out the loudness of tracks that are streamed rather than played from the
+/// library, whose tracks are normalised when they are downloaded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Loudness {
+    /// Tracks play as loud as they were uploaded
+    Off,
+    /// Tracks get a gain from their loudness measurements. Tracks that weren't measured yet are
+    /// measured in the background and play unchanged until then.
+    #[default]
+    Measured,
+    /// Like `measured`, but tracks that weren't measured yet are normalised on the fly with
+    /// ffmpeg's loudnorm filter
+    Loudnorm,
+    /// Like `loudnorm`, but with ffmpeg's dynaudnorm filter, which also evens out loudness within
+    /// a track
+    Dynaudnorm,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Player {
+    pub backend: Backend,
+    /// The player executable. Defaults to the one found at build time.
+    pub path: PathBuf,
+    /// Extra arguments passed to the player
+    pub args: Vec<String>,
+    /// How many times a crashed player is restarted before moving on to the next queue item
+    pub max_restarts: u32,
+    /// How long to wait before restarting a crashed player
+    #[serde(with = "humantime_serde")]
+    pub restart_delay: Duration,
+    /// How streamed tracks are brought to the loudness of `audio.profile`
+    pub loudness: Loudness,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Downloads {
+    /// Where downloaded cassettes are stored. Defaults to `library` in the data directory.
+    pub library: Option<PathBuf>,
+    /// The youtube-dl compatible executable used to fetch songs
+    pub downloader: PathBuf,
+    /// The width and height of the cover embedded in tracks, in pixels
+    pub cover_size: u32,
+    /// How covers that aren't square are made square
+    pub cover_fit: Fit,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Audio {
+    /// The ffmpeg executable
+    pub ffmpeg: PathBuf,
+    /// Where unfinished audio files are kept. Defaults to `work` in the data directory.
+    pub work_dir: Option<PathBuf>,
+    /// The loudness downloaded tracks are normalised to, unless a download asks for another one
+    pub profile: Profile,
+    /// The codec downloaded tracks are encoded with, unless a download asks for another one
+    pub codec: Codec,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 3030))],
+            data_dir: persist::state_dir(),
+            upstream: Upstream::default(),
+            refresh: Refresh::default(),
+            player: Player::default(),
+            downloads: Downloads::default(),
+            audio: Audio::default(),
+        }
+    }
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self {
+            base_url: kasetophono::upstream::BASE_URL.into(),
+        }
+    }
+}
+
+impl Default for Refresh {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(24 * 60 * 60),
+            retry: Duration::from_secs(30),
+            page_size: 25,
+            concurrency: 5,
+            timeout: Duration::from_secs(30),
+            user_agent: concat!("taped/", env!("CARGO_PKG_VERSION")).into(),
+            attempts: 4,
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            allow_partial: true,
+        }
+    }
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Mpv,
+            path: MPV.into(),
+            args: vec![],
+            max_restarts: 3,
+            restart_delay: Duration::from_secs(2),
+            loudness: Loudness::default(),
+        }
+    }
+}
+
+impl Default for Downloads {
+    fn default() -> Self {
+        Self {
+            library: None,
+            downloader: "yt-dlp".into(),
+            cover_size: 512,
+            cover_fit: Fit::default(),
+        }
+    }
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Self {
+            ffmpeg: "ffmpeg".into(),
+            work_dir: None,
+            profile: Profile::default(),
+            codec: Codec::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from the config file and the command line flags, with the flags
+    /// taking precedence
+    pub fn load(args: Args) -> Result<Self> {
+        let path = args.config.clone().or_else(|| {
+            let dir = match env::var_os("XDG_CONFIG_HOME") {
+                Some(dir) => PathBuf::from(dir),
+                None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+            };
+            Some(dir.join("taped/config.toml")).filter(|path| path.exists())
+        });
+
+        let config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.with_args(args)
+    }
+
+    /// Overrides the configuration with the command line flags, leaving out `--config`
+    fn with_args(self, args: Args) -> Result<Self> {
+        let mut config = self;
+        if !args.listen.is_empty() {
+            config.listen = args.listen;
+        }
+        if let Some(data_dir) = args.data_dir {
+            config.data_dir = data_dir;
+        }
+        if let Some(base_url) = args.upstream {
+            config.upstream.base_url = base_url;
+        }
+        if let Some(interval) = args.refresh_interval {
+            config.refresh.interval = interval;
+        }
+        if let Some(concurrency) = args.concurrency {
+            config.refresh.concurrency = concurrency;
+        }
+        if let Some(path) = args.player {
+            config.player.path = path;
+        }
+        config.audio.profile.validate()?;
+        if !(1..=cover::MAX_SIZE).contains(&config.downloads.cover_size) {
+            bail!(
+                "the cover size must be between 1 and {} pixels",
+                cover::MAX_SIZE
+            );
+        }
+        if config.refresh.page_size == 0 {
+            bail!("the page size must be at least 1");
+        }
+        if config.refresh.concurrency == 0 {
+            bail!("the concurrency must be at least 1");
+        }
+        Ok(config)
+    }
+
+    /// The directory downloaded cassettes are stored in
+    pub fn library(&self) -> PathBuf {
+        match &self.downloads.library {
+            Some(library) => library.clone(),
+            None => self.data_dir.join("library"),
+        }
+    }
+
+    /// How downloaded tracks are normalised and encoded by default
+    pub fn encoding(&self) -> Encoding {
+        Encoding {
+            profile: self.audio.profile,
+            codec: self.audio.codec,
+        }
+    }
+
+    /// The directory unfinished audio files are kept in
+    pub fn work_dir(&self) -> PathBuf {
+        match &self.audio.work_dir {
+            Some(work_dir) => work_dir.clone(),
+            None => self.data_dir.join("work"),
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("parsing {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_example() {
+        let config: Config = toml::from_str(include_str!("../config.example.toml")).unwrap();
+
+        assert_eq!(config.listen.len(), 2);
+        assert_eq!(config.refresh.interval, Duration::from_secs(12 * 60 * 60));
+        assert_eq!(config.player.backend, Backend::Mpv);
+        assert_eq!(config.player.loudness, Loudness::Loudnorm);
+        let profile = Profile::Custom {
+            integrated: -16.0,
+            range: 11.0,
+            true_peak: -1.5,
+        };
+        assert_eq!(config.audio.profile, profile);
+        assert_eq!(config.audio.codec, Codec::Opus);
+        assert_eq!(config.downloads.cover_fit, Fit::Pad);
+    }
+
+    #[test]
+    fn cli_overrides() {
+        let args = Args::parse_from(["taped", "--listen", "0.0.0.0:80", "--concurrency", "2"]);
+        let config = Config::default().with_args(args).unwrap();
+
+        assert_eq!(config.listen, vec![SocketAddr::from(([0, 0, 0, 0], 80))]);
+        assert_eq!(config.refresh.concurrency, 2);
+        assert_eq!(config.refresh.page_size, 25);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut config = Config::default();
+        config.refresh.page_size = 0;
+        assert!(config.with_args(Args::parse_from(["taped"])).is_err());
+
+        let args = Args::parse_from(["taped", "--concurrency", "0"]);
+        assert!(Config::default().with_args(args).is_err());
+
+        // Covers can't be larger than the originals they're made from
+        let mut config = Config::default();
+        config.downloads.cover_size = 2048;
+        assert!(config.with_args(Args::parse_from(["taped"])).is_err());
+        let mut config = Config::default();
+        config.downloads.cover_size = 1024;
+        assert!(config.with_args(Args::parse_from(["taped"])).is_ok());
+    }
+}
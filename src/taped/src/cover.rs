@@ -0,0 +1,309 @@
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+use reqwest::Url;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::metrics::Metrics;
+use crate::{config, persist, Result};
+
+/// The sizes covers are resized to. Requested sizes are rounded up to the next one so that the
+/// cache only ever holds a few versions of every image.
+const SIZES: &[u32] = &[64, 128, 256, 512, MAX_SIZE];
+
+pub const DEFAULT_SIZE: u32 = 256;
+
+/// The size originals are fetched at, which nothing is scaled up beyond
+pub const MAX_SIZE: u32 = 1024;
+
+/// The hosts that serve Blogger images, which can be asked for an image of a specific size
+const BLOGGER_HOSTS: &[&str] = &["blogger.googleusercontent.com", "bp.blogspot.com"];
+
+/// Fetches images from upstream and keeps resized versions of them on disk
+pub struct Covers {
+    dir: PathBuf,
+    http: reqwest::Client,
+    metrics: Arc<Metrics>,
+}
+
+/// How a cover that isn't square is made square
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fit {
+    /// Cut off the edges of the longer side
+    #[default]
+    Crop,
+    /// Add black bars along the shorter side
+    Pad,
+}
+
+/// A resized image
+pub struct Image {
+    pub data: Vec<u8>,
+    pub content_type: &'static str,
+}
+
+impl Covers {
+    pub fn new(dir: PathBuf, refresh: &config::Refresh, metrics: Arc<Metrics>) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(refresh.timeout)
+            .user_agent(&refresh.user_agent)
+            .build()?;
+        Ok(Self { dir, http, metrics })
+    }
+
+    /// Returns the image at `url` resized to fit in a square of at least `size` pixels. The
+    /// original is only fetched the first time any size of it is asked for.
+    pub async fn get(&self, url: &str, size: u32) -> Result<Image> {
+        let size = SIZES
+            .iter()
+            .copied()
+            .find(|&s| s >= size)
+            .unwrap_or(SIZES[SIZES.len() - 1]);
+        let key = Uuid::new_v5(&Uuid::NAMESPACE_URL, url.as_bytes());
+
+        let resized_path = self.dir.join(format!("{}-{}", key, size));
+        if let Some(data) = read(&resized_path).await? {
+            return Ok(Image::new(data));
+        }
+
+        let original = self.original(url, key).await?;
+        let data = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+            let data = resize(&original, size)?;
+            persist::write(&resized_path, &data)?;
+            Ok(data)
+        })
+        .await??;
+        Ok(Image::new(data))
+    }
+
+    /// Returns the image at `url` as a square of exactly `size` pixels, as covers are embedded in
+    /// audio files. Unlike `get`, any size can be asked for.
+    pub async fn square(&self, url: &str, size: u32, fit: Fit) -> Result<Image> {
+        let key = Uuid::new_v5(&Uuid::NAMESPACE_URL, url.as_bytes());
+        let fit_name = match fit {
+            Fit::Crop => "crop",
+            Fit::Pad => "pad",
+        };
+        let square_path = self.dir.join(format!("{}-{}-{}", key, size, fit_name));
+        if let Some(data) = read(&square_path).await? {
+            return Ok(Image::new(data));
+        }
+
+        let original = self.original(url, key).await?;
+        let data = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+            let data = square(&original, size, fit)?;
+            persist::write(&square_path, &data)?;
+            Ok(data)
+        })
+        .await??;
+        Ok(Image::new(data))
+    }
+
+    /// The image at `url` as fetched from upstream, which is only done once
+    async fn original(&self, url: &str, key: Uuid) -> Result<Vec<u8>> {
+        let original_path = self.dir.join(key.to_string());
+        if let Some(original) = read(&original_path).await? {
+            return Ok(original);
+        }
+        let original = self.fetch(&blogger_sized(url, MAX_SIZE)).await?;
+        let data = original.clone();
+        tokio::task::spawn_blocking(move || persist::write(&original_path, &data)).await??;
+        Ok(original)
+    }
+
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        let start = Instant::now();
+        let result = async {
+            let response = self.http.get(url).send().await?.error_for_status()?;
+            response.bytes().await
+        }
+        .await;
+        self.metrics
+            .upstream_request("cover", result.is_ok(), start.elapsed());
+        Ok(result?.to_vec())
+    }
+}
+
+impl Image {
+    fn new(data: Vec<u8>) -> Self {
+        let content_type = match image::guess_format(&data) {
+            Ok(ImageFormat::Png) => "image/png",
+            _ => "image/jpeg",
+        };
+        Self { data, content_type }
+    }
+}
+
+async fn read(path: &Path) -> Result<Option<Vec<u8>>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(Some(data)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Shrinks an image to fit in a `size`x`size` square
+fn resize(original: &[u8], size: u32) -> Result<Vec<u8>> {
+    let mut image = image::load_from_memory(original)?;
+    if image.width() > size || image.height() > size {
+        image = image.thumbnail(size, size);
+    }
+    encode(&image)
+}
+
+/// Scales an image to a `size`x`size` square, cropping or padding it if it isn't square. GIFs
+/// are reduced to their first frame.
+fn square(original: &[u8], size: u32, fit: Fit) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(original)?;
+    let image = match fit {
+        Fit::Crop => image.resize_to_fill(size, size, FilterType::CatmullRom),
+        Fit::Pad => {
+            let image = image.resize(size, size, FilterType::CatmullRom);
+            let mut canvas = RgbaImage::from_pixel(size, size, Rgba([0, 0, 0, 255]));
+            let x = (size - image.width()) / 2;
+            let y = (size - image.height()) / 2;
+            imageops::overlay(&mut canvas, &image.to_rgba8(), x.into(), y.into());
+            DynamicImage::ImageRgba8(canvas)
+        }
+    };
+    encode(&image)
+}
+
+/// Encodes images with transparency as PNG and everything else as JPEG. GIFs and WebPs always
+/// have an alpha channel, but it is rarely used.
+fn encode(image: &DynamicImage) -> Result<Vec<u8>> {
+    let transparent = match image {
+        image if !image.color().has_alpha() => false,
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().any(|pixel| pixel[3] < u8::MAX),
+        image => image.to_rgba16().pixels().any(|pixel| pixel[3] < u16::MAX),
+    };
+    let mut data = vec![];
+    if transparent {
+        image.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?;
+    } else {
+        let image = DynamicImage::ImageRgb8(image.to_rgb8());
+        JpegEncoder::new_with_quality(&mut data, 85).encode_image(&image)?;
+    }
+    Ok(data)
+}
+
+/// Rewrites the URL of a Blogger image to ask for a version of it that fits in a `size`x`size`
+/// square. Any other URL is returned unchanged.
+fn blogger_sized(url: &str, size: u32) -> String {
+    let host = Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string));
+    let blogger = match host {
+        Some(host) => BLOGGER_HOSTS
+            .iter()
+            .any(|h| host == *h || host.ends_with(&format!(".{}", h))),
+        None => false,
+    };
+    if !blogger {
+        return url.to_string();
+    }
+
+    // Newer URLs put the options after an `=` at the end, e.g. `.../AVvXsEi...=s72-w640-h340-c`
+    if let Some((base, options)) = url.rsplit_once('=') {
+        if is_size_options(options) {
+            return format!("{}=s{}", base, size);
+        }
+    }
+    // Older URLs put them in the second to last path segment, e.g. `.../s72-c/cover.jpg`
+    let mut segments: Vec<&str> = url.split('/').collect();
+    if segments.len() >= 2 {
+        let options = segments.len() - 2;
+        if is_size_options(segments[options]) {
+            let sized = format!("s{}", size);
+            segments[options] = &sized;
+            return segments.join("/");
+        }
+    }
+    url.to_string()
+}
+
+/// Whether a string looks like Blogger image options, e.g. `s72-c` or `w640-h340`
+fn is_size_options(options: &str) -> bool {
+    let valid = |option: &str| {
+        let digits = option.trim_start_matches(|c: char| c.is_ascii_lowercase());
+        digits.len() < option.len() && digits.chars().all(|c| c.is_ascii_digit())
+    };
+    let sized = |option: &str| option.ends_with(|c: char| c.is_ascii_digit());
+    options.split('-').all(valid) && options.split('-').any(sized)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blogger_urls() {
+        assert_eq!(
+            blogger_sized(
+                "https://blogger.googleusercontent.com/img/a/AVvXsEiK=s72-w640-h340-c",
+                512
+            ),
+            "https://blogger.googleusercontent.com/img/a/AVvXsEiK=s512"
+        );
+        assert_eq!(
+            blogger_sized(
+                "https://3.bp.blogspot.com/-xTPG/WbGO/AAAA/UY9f/s600/kasetophono.png",
+                64
+            ),
+            "https://3.bp.blogspot.com/-xTPG/WbGO/AAAA/UY9f/s64/kasetophono.png"
+        );
+        assert_eq!(
+            blogger_sized(
+                "https://blogger.googleusercontent.com/img/b/R29vZ2xl/cover.jpg",
+                64
+            ),
+            "https://blogger.googleusercontent.com/img/b/R29vZ2xl/cover.jpg"
+        );
+        assert_eq!(
+            blogger_sized("https://example.com/s72-c/cover.jpg", 64),
+            "https://example.com/s72-c/cover.jpg"
+        );
+    }
+
+    #[test]
+    fn resize_to_fit() {
+        let image = DynamicImage::new_rgb8(400, 200);
+        let mut png = vec![];
+        image
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let resized = resize(&png, 100).unwrap();
+        let resized = image::load_from_memory(&resized).unwrap();
+        assert_eq!((resized.width(), resized.height()), (100, 50));
+        assert_eq!(Image::new(png).content_type, "image/png");
+    }
+
+    #[test]
+    fn square_covers() {
+        let gif = include_bytes!("../../../resources/album-art.gif");
+        let cropped = square(gif, 300, Fit::Crop).unwrap();
+        assert_eq!(Image::new(cropped.clone()).content_type, "image/jpeg");
+        let cropped = image::load_from_memory(&cropped).unwrap();
+        assert_eq!((cropped.width(), cropped.height()), (300, 300));
+
+        let mut wide = DynamicImage::new_rgb8(400, 200);
+        wide.as_mut_rgb8().unwrap().fill(255);
+        let mut png = vec![];
+        wide.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let padded = square(&png, 100, Fit::Pad).unwrap();
+        let padded = image::load_from_memory(&padded).unwrap().to_rgb8();
+        assert_eq!(padded.dimensions(), (100, 100));
+        // Black bars above and below, the white image in the middle
+        assert!(padded.get_pixel(50, 5)[0] < 16);
+        assert!(padded.get_pixel(50, 50)[0] > 240);
+    }
+}
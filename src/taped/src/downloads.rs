@@ -0,0 +1,733 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context};
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use kasetophono::{Cassette, Song};
+
+use crate::audio::{Chapter, Codec, Encoding, Ffmpeg, Profile, Tape, TapeFormat};
+use crate::catalog::Catalog;
+use crate::config::{self, Config};
+use crate::cover::Covers;
+use crate::library::{self, Library, Location};
+use crate::loudness::{self, Measurement};
+use crate::sides::{self, TapeLength};
+use crate::tags::{self, Picture, Tags};
+use crate::{persist, Result};
+
+/// A cheaply cloneable handle for queueing downloads and following their progress
+#[derive(Clone)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    jobs: Mutex<Jobs>,
+    /// Where the jobs are saved on every change, so that downloads resume after a restart
+    path: PathBuf,
+    /// Wakes the download task up when a job is queued
+    queued: Notify,
+    /// Wakes the task that saves the jobs up when they change
+    changed: Notify,
+    /// How tracks are encoded unless a job asks otherwise
+    encoding: Encoding,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Jobs {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+/// A request to download all songs of a cassette into the library
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub cassette: Uuid,
+    /// The name of the cassette, so that the job stays readable if the cassette leaves the catalog
+    pub name: String,
+    #[serde(flatten)]
+    pub state: State,
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// The format the whole cassette is also exported in as a single file, if any
+    #[serde(default)]
+    pub tape: Option<TapeFormat>,
+    /// The length of real tape the cassette is also rendered for as two sides, if any
+    #[serde(default)]
+    pub sides: Option<TapeLength>,
+    /// How many songs the cassette has, once known
+    pub tracks: Option<usize>,
+    /// How many tracks are in the library
+    pub done: usize,
+    /// The tracks that could not be downloaded
+    pub errors: Vec<TrackError>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum State {
+    Queued,
+    /// Working on the given 1-based track, or on the cassette as a whole
+    Running {
+        track: Option<usize>,
+        step: Option<Step>,
+        /// How far the step got, in percent, if that is known
+        progress: Option<f32>,
+    },
+    /// Every track was attempted, see `errors` for the ones that failed
+    Finished,
+    /// The job failed as a whole, e.g. because the songs of the cassette could not be listed
+    Failed {
+        error: String,
+    },
+    Cancelled,
+}
+
+impl State {
+    fn is_active(&self) -> bool {
+        matches!(self, State::Queued | State::Running { .. })
+    }
+}
+
+/// What a running job is doing with the current track
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Download,
+    /// The first normalisation pass
+    Measure,
+    /// The second normalisation pass
+    Normalize,
+    Tag,
+    /// Joining the tracks into a single file
+    Tape,
+    /// Rendering the sides of a real tape
+    Sides,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrackError {
+    /// The 1-based track number
+    pub track: usize,
+    pub title: String,
+    pub error: String,
+}
+
+impl Handle {
+    /// Loads the saved jobs, putting the ones that were interrupted back in the queue
+    fn load(path: PathBuf, encoding: Encoding) -> Self {
+        let mut jobs = match persist::load::<Jobs>(&path) {
+            Ok(jobs) => jobs.unwrap_or_default(),
+            Err(err) => {
+                info!("discarding saved downloads: {}", err);
+                Jobs::default()
+            }
+        };
+        for job in &mut jobs.jobs {
+            if let State::Running { .. } = job.state {
+                job.state = State::Queued;
+            }
+        }
+        let shared = Shared {
+            jobs: Mutex::new(jobs),
+            path,
+            queued: Notify::new(),
+            changed: Notify::new(),
+            encoding,
+        };
+        Self {
+            shared: Arc::new(shared),
+        }
+    }
+
+    pub fn jobs(&self) -> Vec<Job> {
+        self.shared.jobs.lock().unwrap().jobs.clone()
+    }
+
+    pub fn job(&self, id: u64) -> Option<Job> {
+        let jobs = self.shared.jobs.lock().unwrap();
+        jobs.jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Queues a download of a cassette, normalised and encoded as configured unless the request
+    /// says otherwise, and optionally exported as a tape or as the sides of a real one. Returns the
+    /// job and whether it was created, which it isn't if the cassette is already being downloaded
+    /// the same way.
+    pub fn start(
+        &self,
+        cassette: &Cassette,
+        profile: Option<Profile>,
+        codec: Option<Codec>,
+        tape: Option<TapeFormat>,
+        sides: Option<TapeLength>,
+    ) -> (Job, bool) {
+        let encoding = Encoding {
+            profile: profile.unwrap_or(self.shared.encoding.profile),
+            codec: codec.unwrap_or(self.shared.encoding.codec),
+        };
+        let mut jobs = self.shared.jobs.lock().unwrap();
+        let active = jobs.jobs.iter().find(|job| {
+            job.cassette == cassette.uuid
+                && job.encoding == encoding
+                && job.tape == tape
+                && job.sides == sides
+                && job.state.is_active()
+        });
+        if let Some(job) = active {
+            return (job.clone(), false);
+        }
+
+        jobs.next_id += 1;
+        let job = Job {
+            id: jobs.next_id,
+            cassette: cassette.uuid,
+            name: cassette.name.clone(),
+            state: State::Queued,
+            encoding,
+            tape,
+            sides,
+            tracks: None,
+            done: 0,
+            errors: vec![],
+            created_at: Utc::now(),
+            finished_at: None,
+        };
+        jobs.jobs.push(job.clone());
+        self.changed();
+        self.shared.queued.notify_one();
+        (job, true)
+    }
+
+    /// Cancels a queued or running job, or forgets a finished one. A running job stops once it is
+    /// done with the track it is working on.
+    pub fn cancel(&self, id: u64) -> bool {
+        let mut jobs = self.shared.jobs.lock().unwrap();
+        let index = match jobs.jobs.iter().position(|job| job.id == id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let job = &mut jobs.jobs[index];
+        if job.state.is_active() {
+            job.state = State::Cancelled;
+            job.finished_at = Some(Utc::now());
+        } else {
+            jobs.jobs.remove(index);
+        }
+        self.changed();
+        true
+    }
+
+    /// Marks the oldest queued job as running and returns it. Jobs that were interrupted go over
+    /// every track again, so they start counting from scratch.
+    fn next(&self) -> Option<Job> {
+        let mut jobs = self.shared.jobs.lock().unwrap();
+        let job = jobs
+            .jobs
+            .iter_mut()
+            .find(|job| job.state == State::Queued)?;
+        job.state = State::Running {
+            track: None,
+            step: None,
+            progress: None,
+        };
+        job.done = 0;
+        job.errors.clear();
+        let job = job.clone();
+        self.changed();
+        Some(job)
+    }
+
+    /// Updates a running job. Returns false if the job is no longer running, i.e. it was cancelled
+    /// in the meantime.
+    fn update(&self, id: u64, update: impl FnOnce(&mut Job)) -> bool {
+        let mut jobs = self.shared.jobs.lock().unwrap();
+        let job = jobs.jobs.iter_mut().find(|job| job.id == id);
+        match job {
+            Some(job) if matches!(job.state, State::Running { .. }) => update(job),
+            _ => return false,
+        }
+        self.changed();
+        true
+    }
+
+    /// Reports how far a running job got with a track. Progress isn't saved since running jobs
+    /// start over after a restart anyway.
+    fn report(&self, id: u64, track: Option<usize>, step: Step, progress: Option<f32>) {
+        let mut jobs = self.shared.jobs.lock().unwrap();
+        let job = jobs.jobs.iter_mut().find(|job| job.id == id);
+        if let Some(job) = job.filter(|job| matches!(job.state, State::Running { .. })) {
+            job.state = State::Running {
+                track,
+                step: Some(step),
+                progress,
+            };
+        }
+    }
+
+    fn finish(&self, id: u64, result: Result<()>) {
+        self.update(id, |job| {
+            job.state = match result {
+                Ok(()) => State::Finished,
+                Err(err) => {
+                    info!("failed to download {}: {:#}", job.name, err);
+                    State::Failed {
+                        error: format!("{:#}", err),
+                    }
+                }
+            };
+            job.finished_at = Some(Utc::now());
+        });
+    }
+
+    /// Has the jobs saved by the task that saves them, so that changing them never waits for the
+    /// disk
+    fn changed(&self) {
+        self.shared.changed.notify_one();
+    }
+
+    /// Saves the jobs every time they change. Changes made while saving are saved right after.
+    async fn save_changes(self) {
+        loop {
+            self.shared.changed.notified().await;
+            self.save().await;
+        }
+    }
+
+    async fn save(&self) {
+        let jobs = self.shared.jobs.lock().unwrap().clone();
+        let path = self.shared.path.clone();
+        let saved = tokio::task::spawn_blocking(move || persist::save(&path, &jobs)).await;
+        if let Err(err) = saved.map_err(Into::into).and_then(|saved| saved) {
+            info!("failed to save downloads: {}", err);
+        }
+    }
+}
+
+/// Spawns the task that downloads the queued cassettes, one at a time
+pub fn spawn(
+    catalog: Arc<ArcSwap<Catalog>>,
+    covers: Arc<Covers>,
+    library: Library,
+    loudness: Arc<loudness::Cache>,
+    config: &Config,
+) -> Handle {
+    let path = config.data_dir.join("downloads.json");
+    let handle = Handle::load(path, config.encoding());
+    let downloader = Downloader {
+        catalog,
+        covers,
+        library,
+        loudness,
+        ffmpeg: Ffmpeg::new(&config.audio, config.work_dir()),
+        config: config.downloads.clone(),
+    };
+    tokio::spawn(handle.clone().save_changes());
+    tokio::spawn(downloader.run(handle.clone()));
+    handle
+}
+
+struct Downloader {
+    catalog: Arc<ArcSwap<Catalog>>,
+    covers: Arc<Covers>,
+    library: Library,
+    loudness: Arc<loudness::Cache>,
+    ffmpeg: Ffmpeg,
+    config: config::Downloads,
+}
+
+impl Downloader {
+    async fn run(self, handle: Handle) {
+        loop {
+            match handle.next() {
+                Some(job) => {
+                    info!("downloading {}", job.name);
+                    let result = self.download(&handle, &job).await;
+                    handle.finish(job.id, result);
+                }
+                None => handle.shared.queued.notified().await,
+            }
+        }
+    }
+
+    async fn download(&self, handle: &Handle, job: &Job) -> Result<()> {
+        let cassette = self.catalog.load().cassettes.get(&job.cassette).cloned();
+        let mut cassette = cassette.ok_or_else(|| anyhow!("{} left the catalog", job.name))?;
+        if cassette.videos.is_empty() {
+            let downloader = self.config.downloader.clone();
+            cassette = tokio::task::spawn_blocking(move || {
+                cassette.fill_songs_with(downloader)?;
+                Ok::<_, anyhow::Error>(cassette)
+            })
+            .await?
+            .context("listing songs")?;
+        }
+
+        let total = cassette.videos.len();
+        if !handle.update(job.id, |job| job.tracks = Some(total)) {
+            return Ok(());
+        }
+
+        let dir = self.library.dir(&cassette);
+        tokio::fs::create_dir_all(&dir).await?;
+        let (library, songs) = (self.library.clone(), cassette.clone());
+        tokio::task::spawn_blocking(move || library.save_songs(&songs)).await??;
+        let cover = match &cassette.image_url {
+            Some(url) => match self.cover(url, &dir).await {
+                Ok(path) => Some(path),
+                Err(err) => {
+                    info!("downloading {} without a cover: {:#}", cassette.name, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        // Read once rather than for every track it is embedded in
+        let picture = match &cover {
+            Some(path) => match Picture::load(path).await {
+                Ok(picture) => Some(picture),
+                Err(err) => {
+                    info!("tagging {} without a cover: {:#}", cassette.name, err);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        for (index, song) in cassette.videos.iter().enumerate() {
+            let track = index + 1;
+            let running = State::Running {
+                track: Some(track),
+                step: None,
+                progress: None,
+            };
+            if !handle.update(job.id, |job| job.state = running) {
+                info!("cancelled download of {}", cassette.name);
+                return Ok(());
+            }
+
+            let file = library::track_file(track, total, song, job.encoding.codec);
+            let path = dir.join(file);
+            let result = match tokio::fs::metadata(&path).await.is_ok() {
+                true => Ok(()),
+                false => {
+                    let picture = picture.as_ref();
+                    let report =
+                        |step, progress| handle.report(job.id, Some(track), step, progress);
+                    let track = Track {
+                        number: track,
+                        song,
+                        encoding: job.encoding,
+                    };
+                    let result = self.track(&cassette, track, picture, &path, report);
+                    result.await
+                }
+            };
+            handle.update(job.id, |job| match result {
+                Ok(()) => job.done += 1,
+                Err(err) => {
+                    info!("failed to download {}: {:#}", song.title, err);
+                    job.errors.push(TrackError {
+                        track,
+                        title: song.title.clone(),
+                        error: format!("{:#}", err),
+                    });
+                }
+            });
+        }
+
+        let cover = cover.as_deref();
+        self.export(handle, job, &cassette, &dir, cover).await
+    }
+
+    /// Renders the tracks in the library as a tape or as the sides of a real one, if the job asks
+    /// for it. Songs that failed to download are left out.
+    async fn export(
+        &self,
+        handle: &Handle,
+        job: &Job,
+        cassette: &Cassette,
+        dir: &Path,
+        cover: Option<&Path>,
+    ) -> Result<()> {
+        if job.tape.is_none() && job.sides.is_none() {
+            return Ok(());
+        }
+        let start = |step| {
+            let running = State::Running {
+                track: None,
+                step: Some(step),
+                progress: None,
+            };
+            let started = handle.update(job.id, |job| job.state = running);
+            if !started {
+                info!("cancelled download of {}", cassette.name);
+            }
+            started
+        };
+        let report = |step| move |progress| handle.report(job.id, None, step, Some(progress));
+
+        let tracks = self.library.tracks(cassette).await;
+        let mut chapters = vec![];
+        for track in &tracks {
+            if let Location::Local { path } = &track.location {
+                chapters.push(Chapter {
+                    path,
+                    number: track.number,
+                    title: &track.song.title,
+                    duration: self.ffmpeg.duration(path).await?,
+                });
+            }
+        }
+
+        if let Some(format) = job.tape {
+            if !start(Step::Tape) {
+                return Ok(());
+            }
+            let tape = Tape {
+                title: cassette.name.clone(),
+                chapters: chapters.clone(),
+                leader: Duration::ZERO,
+                format,
+            };
+            let path = dir.join(format!("{}.{}", cassette.safe_name, format.extension()));
+            self.ffmpeg
+                .render_tape(&tape, &path, cassette, cover, report(Step::Tape))
+                .await
+                .context("exporting tape")?;
+        }
+
+        if let Some(length) = job.sides {
+            if !start(Step::Sides) {
+                return Ok(());
+            }
+            let durations: Vec<Duration> = chapters.iter().map(|c| c.duration).collect();
+            let sides = sides::pack(&durations, length);
+            for (side, indices) in [("A", &sides.a), ("B", &sides.b)] {
+                if indices.is_empty() {
+                    continue;
+                }
+                let tape = Tape {
+                    title: format!("{} - Side {}", cassette.name, side),
+                    chapters: indices.iter().map(|&index| chapters[index]).collect(),
+                    leader: sides::LEADER,
+                    format: TapeFormat::Flac,
+                };
+                let file = format!("{} - Side {}.flac", cassette.safe_name, side);
+                self.ffmpeg
+                    .render_tape(&tape, &dir.join(file), cassette, cover, report(Step::Sides))
+                    .await
+                    .with_context(|| format!("rendering side {}", side))?;
+            }
+            let titles: Vec<(usize, &str)> = chapters.iter().map(|c| (c.number, c.title)).collect();
+            let jcard = sides::jcard(cassette, &titles, &durations, &sides, length);
+            let path = dir.join(format!("{} - J-card.svg", cassette.safe_name));
+            tokio::task::spawn_blocking(move || persist::write(&path, jcard.as_bytes())).await??;
+        }
+        Ok(())
+    }
+
+    /// Saves the cover next to the tracks of the cassette, squared as configured, and returns its
+    /// path
+    async fn cover(&self, url: &str, dir: &Path) -> Result<PathBuf> {
+        let (size, fit) = (self.config.cover_size, self.config.cover_fit);
+        let image = self.covers.square(url, size, fit).await?;
+        let extension = match image.content_type {
+            "image/png" => "png",
+            _ => "jpg",
+        };
+        let path = dir.join(format!("cover.{}", extension));
+        let write = {
+            let path = path.clone();
+            move || persist::write(&path, &image.data)
+        };
+        tokio::task::spawn_blocking(write).await??;
+        Ok(path)
+    }
+
+    /// Downloads a song, normalises its loudness and tags it as a track of the cassette
+    async fn track(
+        &self,
+        cassette: &Cassette,
+        track: Track<'_>,
+        picture: Option<&Picture>,
+        path: &Path,
+        report: impl Fn(Step, Option<f32>),
+    ) -> Result<()> {
+        let song = track.song;
+        let work_dir = self.ffmpeg.tempdir()?;
+
+        report(Step::Download, None);
+        let url = format!("https://www.youtube.com/watch?v={}", song.id);
+        let status = Command::new(&self.config.downloader)
+            .kill_on_drop(true)
+            .args(["--quiet", "--no-playlist", "--add-metadata"])
+            .args(["--format", "bestaudio/best", "--output"])
+            .arg(work_dir.path().join("source.%(format_id)s.%(ext)s"))
+            .arg(&url)
+            .status()
+            .await
+            .with_context(|| format!("running {}", self.config.downloader.display()))?;
+        if !status.success() {
+            bail!(
+                "{} failed with {}",
+                self.config.downloader.display(),
+                status
+            );
+        }
+        let (source, format) = downloaded_file(work_dir.path()).await?;
+
+        let duration = song.duration.map(Duration::from_secs);
+        let profile = track.encoding.profile;
+        let loudness = match self.loudness.get(&song.id, &format) {
+            Some(measurement) => measurement.for_profile(profile),
+            None => {
+                let measure = |progress| report(Step::Measure, Some(progress));
+                let loudness = self
+                    .ffmpeg
+                    .measure_loudness(&source, profile, duration, measure);
+                let loudness = loudness.await?;
+                let measurement = Measurement::new(loudness, profile);
+                self.loudness.insert(&song.id, &format, measurement).await;
+                loudness
+            }
+        };
+        let extension = track.encoding.codec.extension();
+        let normalized = work_dir.path().join(format!("normalized.{}", extension));
+        let normalize = |progress| report(Step::Normalize, Some(progress));
+        self.ffmpeg
+            .correct_loudness(
+                &source,
+                &normalized,
+                loudness,
+                track.encoding,
+                duration,
+                normalize,
+            )
+            .await?;
+
+        report(Step::Tag, None);
+        let tags = Tags::new(cassette, track.number, song)?;
+        tags::write(&normalized, path, &tags, picture).await
+    }
+}
+
+/// A song to download as a track of a cassette
+struct Track<'a> {
+    /// The 1-based track number
+    number: usize,
+    song: &'a Song,
+    encoding: Encoding,
+}
+
+/// Finds the file the downloader wrote and the id of the format it picked, which are both part of
+/// its name
+async fn downloaded_file(dir: &Path) -> Result<(PathBuf, String)> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if let Some(format) = path
+            .file_name()
+            .and_then(|name| source_format(name.to_str()?))
+        {
+            let format = format.to_string();
+            return Ok((path, format));
+        }
+    }
+    bail!("the downloader didn't write any file")
+}
+
+/// Extracts the format id from the name of a downloaded file, e.g. `251` from `source.251.webm`
+fn source_format(name: &str) -> Option<&str> {
+    let (format, _extension) = name.strip_prefix("source.")?.rsplit_once('.')?;
+    Some(format).filter(|format| !format.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cassette(name: &str) -> Cassette {
+        Cassette {
+            uuid: Uuid::new_v5(&Uuid::NAMESPACE_URL, name.as_bytes()),
+            name: name.into(),
+            safe_name: name.into(),
+            path: format!("2021/03/cassettes/{}", name),
+            created_at: "2021-03-01T00:00:00Z".into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("downloads.json");
+        let handle = Handle::load(path.clone(), Encoding::default());
+
+        let (first, created) = handle.start(&cassette("Nero"), None, None, None, None);
+        assert!(created);
+        let (again, created) = handle.start(&cassette("Nero"), None, Some(Codec::Mp3), None, None);
+        assert!(!created);
+        assert_eq!(again.id, first.id);
+        let (flac, created) = handle.start(&cassette("Nero"), None, Some(Codec::Flac), None, None);
+        assert!(created);
+        assert_eq!(flac.encoding.codec, Codec::Flac);
+        let tape = Some(TapeFormat::M4b);
+        let (tape, created) = handle.start(&cassette("Nero"), None, None, tape, None);
+        assert!(created);
+        assert_ne!(tape.id, first.id);
+        let sides = Some(TapeLength::C60);
+        let (_, created) = handle.start(&cassette("Nero"), None, None, None, sides);
+        assert!(created);
+        let (other, _) = handle.start(
+            &cassette("Ilios"),
+            Some(Profile::Streaming),
+            None,
+            None,
+            None,
+        );
+
+        assert_eq!(handle.next().unwrap().id, first.id);
+        let error = TrackError {
+            track: 2,
+            title: "Second".into(),
+            error: "unavailable".into(),
+        };
+        assert!(handle.update(first.id, |job| {
+            job.done = 1;
+            job.errors.push(error);
+        }));
+        assert!(handle.cancel(other.id));
+        assert!(!handle.update(other.id, |job| job.done = 1));
+
+        // Running jobs go back to the queue after a restart
+        handle.save().await;
+        let handle = Handle::load(path, Encoding::default());
+        assert_eq!(handle.job(first.id).unwrap().state, State::Queued);
+        assert_eq!(handle.job(other.id).unwrap().state, State::Cancelled);
+        // and go over every track again without counting the ones they already did twice
+        let resumed = handle.next().unwrap();
+        assert_eq!(resumed.id, first.id);
+        assert_eq!(resumed.done, 0);
+        assert!(resumed.errors.is_empty());
+        assert!(handle.cancel(other.id));
+        assert!(handle.job(other.id).is_none());
+    }
+
+    #[test]
+    fn source_formats() {
+        assert_eq!(source_format("source.251.webm"), Some("251"));
+        assert_eq!(source_format("source.251-drc.webm"), Some("251-drc"));
+        assert_eq!(source_format("source.webm"), None);
+    }
+}
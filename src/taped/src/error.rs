@@ -0,0 +1,173 @@
+use std::fmt::Display;
+
+use axum::body::HttpBody;
+use axum::extract::{self, FromRequest, RequestParts};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{async_trait, BoxError};
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The stable, machine readable identifiers of API errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request body, path or query string could not be understood
+    InvalidRequest,
+    /// There is no endpoint at the requested path
+    NotFound,
+    CassetteNotFound,
+    SongNotFound,
+    TrackNotFound,
+    QueueItemNotFound,
+    /// The cassette has no cover image
+    CoverNotFound,
+    DownloadNotFound,
+    /// A catalog refresh is already running
+    RefreshRunning,
+    /// The player task failed to carry out the request
+    PlayerError,
+    /// An image could not be fetched from upstream
+    UpstreamError,
+    /// Something unexpected went wrong
+    InternalError,
+}
+
+impl ErrorCode {
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound
+            | ErrorCode::CassetteNotFound
+            | ErrorCode::SongNotFound
+            | ErrorCode::TrackNotFound
+            | ErrorCode::QueueItemNotFound
+            | ErrorCode::CoverNotFound
+            | ErrorCode::DownloadNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::RefreshRunning => StatusCode::CONFLICT,
+            ErrorCode::UpstreamError => StatusCode::BAD_GATEWAY,
+            ErrorCode::PlayerError | ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// An error as returned by the API, serialized as `{"code": ..., "message": ...}`
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    /// A human readable description of the error
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Display) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    pub fn player(err: anyhow::Error) -> Self {
+        info!("player request failed: {:#}", err);
+        Self::new(ErrorCode::PlayerError, format!("{:#}", err))
+    }
+
+    pub fn upstream(err: anyhow::Error) -> Self {
+        info!("upstream request failed: {:#}", err);
+        Self::new(ErrorCode::UpstreamError, format!("{:#}", err))
+    }
+
+    pub fn internal(err: anyhow::Error) -> Self {
+        info!("request failed: {:#}", err);
+        Self::new(ErrorCode::InternalError, format!("{:#}", err))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.code.status(), axum::Json(self)).into_response()
+    }
+}
+
+/// Like `axum::Json`, but rejects invalid bodies with an `ApiError`
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[async_trait]
+impl<T, B> FromRequest<B> for Json<T>
+where
+    T: DeserializeOwned,
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        match axum::Json::from_request(req).await {
+            Ok(axum::Json(value)) => Ok(Json(value)),
+            Err(err) => Err(ApiError::new(ErrorCode::InvalidRequest, err)),
+        }
+    }
+}
+
+/// Like `axum::extract::Path`, but rejects invalid parameters with an `ApiError`
+pub struct Path<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for Path<T>
+where
+    T: DeserializeOwned + Send,
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        match extract::Path::from_request(req).await {
+            Ok(extract::Path(value)) => Ok(Path(value)),
+            Err(err) => Err(ApiError::new(ErrorCode::InvalidRequest, err)),
+        }
+    }
+}
+
+/// Like `axum::extract::Query`, but rejects invalid query strings with an `ApiError`
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for Query<T>
+where
+    T: DeserializeOwned,
+    B: Send,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        match extract::Query::from_request(req).await {
+            Ok(extract::Query(value)) => Ok(Query(value)),
+            Err(err) => Err(ApiError::new(ErrorCode::InvalidRequest, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_body() {
+        let err = ApiError::new(ErrorCode::CassetteNotFound, "no such cassette");
+        let body = serde_json::to_value(&err).unwrap();
+
+        assert_eq!(err.code.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            body,
+            serde_json::json!({"code": "cassette_not_found", "message": "no such cassette"})
+        );
+    }
+}
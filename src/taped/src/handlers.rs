@@ -1,68 +1,491 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::BTreeMap;
 
-use axum::extract::{Extension, Path};
-use axum::http::StatusCode;
-use axum::response::{Headers, Json};
-use http::{header::HeaderName, Uri};
+use axum::extract::Extension;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{self, KeepAlive, Sse};
+use axum::response::{Headers, IntoResponse, Response};
+use futures::stream::{self, Stream, StreamExt};
+use http::Uri;
 use include_dir::{include_dir, Dir};
-use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 use uuid::Uuid;
 
-use kasetophono::Cassette;
+use kasetophono::{Cassette, Song};
 
-use crate::ServerState;
+use crate::audio::{Codec, Profile, TapeFormat};
+use crate::catalog::{self, Cassettes};
+use crate::downloads::Job;
+use crate::error::{ApiError, ErrorCode, Json, Path, Query};
+use crate::loudness::Measurement;
+use crate::player::Status;
+use crate::queue::{Order, Queue, Repeat, Source};
+use crate::sides::TapeLength;
+use crate::{cache, cover};
+use crate::{refresh, ServerState};
 
 static ROOT: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/assets");
 
-include!(concat!(env!("OUT_DIR"), "/paths.rs"));
+type Result<T> = std::result::Result<T, ApiError>;
 
-pub async fn play(Path(uuid): Path<Uuid>, Extension(state): Extension<Arc<RwLock<ServerState>>>) {
-    let mut state = state.write();
+/// Covers only change when upstream replaces the image of a cassette, which hardly ever happens
+const COVER_CACHE_CONTROL: &str = "public, max-age=2592000";
 
-    if let Some(mut handle) = state.mpv_process.take() {
-        handle.kill().expect("failed to kill previous mpv process");
+/// Lists the cassettes that match the query string, one page at a time
+pub async fn list(
+    Query(query): Query<catalog::Query>,
+    uri: Uri,
+    headers: HeaderMap,
+    Extension(state): Extension<ServerState>,
+) -> Result<Response> {
+    let catalog = state.catalog.load();
+    let etag = cache::etag(&catalog);
+    // Keys are namespaced per endpoint, since any query string is accepted here
+    let key = format!("list?{}", uri.query().unwrap_or_default());
+    let response = cache::respond(&headers, &etag, || {
+        let page = || catalog.query(&query);
+        state.responses.get(catalog.generation, &key, page)
+    });
+    response.map_err(ApiError::internal)
+}
+
+pub async fn cassette(
+    Path(uuid): Path<Uuid>,
+    headers: HeaderMap,
+    Extension(state): Extension<ServerState>,
+) -> Result<Response> {
+    let catalog = state.catalog.load();
+    let cassette = match catalog.cassettes.get(&uuid) {
+        Some(cassette) => cassette,
+        None => return Err(cassette_not_found(uuid)),
+    };
+    let etag = cache::etag(&catalog);
+    let response = cache::respond(&headers, &etag, || {
+        let key = format!("cassette/{}", uuid);
+        state.responses.get(catalog.generation, &key, || cassette)
+    });
+    response.map_err(ApiError::internal)
+}
+
+#[derive(Deserialize)]
+pub struct CoverOptions {
+    /// The size of the square the cover must fit in, in pixels
+    size: Option<u32>,
+}
+
+/// Serves the cover of a cassette, resized and cached by taped
+pub async fn cover(
+    Path(uuid): Path<Uuid>,
+    Query(options): Query<CoverOptions>,
+    Extension(state): Extension<ServerState>,
+) -> Result<Response> {
+    let url = match state.catalog.load().cassettes.get(&uuid) {
+        Some(cassette) => cassette.image_url.clone(),
+        None => return Err(cassette_not_found(uuid)),
+    };
+    let url = url.ok_or_else(|| {
+        let message = format!("cassette {} has no cover", uuid);
+        ApiError::new(ErrorCode::CoverNotFound, message)
+    })?;
+    image(&state, &url, options.size).await
+}
+
+/// Serves the kasetophono logo, so that the web UI doesn't need to hotlink it
+pub async fn logo(
+    Query(options): Query<CoverOptions>,
+    Extension(state): Extension<ServerState>,
+) -> Result<Response> {
+    image(&state, kasetophono::upstream::LOGO_URL, options.size).await
+}
+
+async fn image(state: &ServerState, url: &str, size: Option<u32>) -> Result<Response> {
+    let size = size.unwrap_or(cover::DEFAULT_SIZE);
+    let image = state.covers.get(url, size).await;
+    let image = image.map_err(ApiError::upstream)?;
+    let headers = Headers([
+        (http::header::CONTENT_TYPE, image.content_type),
+        (http::header::CACHE_CONTROL, COVER_CACHE_CONTROL),
+    ]);
+    Ok((headers, image.data).into_response())
+}
+
+/// What to play or add to the queue
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// A cassette by its UUID
+    Cassette(Uuid),
+    /// A song by its video id
+    Song(String),
+}
+
+#[derive(Deserialize)]
+pub struct Play {
+    #[serde(flatten)]
+    target: Target,
+    /// Defaults to shuffled for whole cassettes and to in order otherwise
+    order: Option<Order>,
+    /// The 1-based track number to start a cassette from
+    track: Option<usize>,
+}
+
+/// The id of the queue item created by a request
+#[derive(Serialize)]
+pub struct Created {
+    id: u64,
+}
+
+/// Plays a cassette or song right away, putting it after the current queue item
+pub async fn play(
+    Extension(state): Extension<ServerState>,
+    Json(request): Json<Play>,
+) -> Result<Json<Created>> {
+    let (source, order) = resolve(&state, request)?;
+    let id = state.player.play(source, order).await;
+    let id = id.map_err(ApiError::player)?;
+    Ok(Json(Created { id }))
+}
+
+pub async fn stop(Extension(state): Extension<ServerState>) -> Result<StatusCode> {
+    state.player.stop().await.map_err(ApiError::player)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn next(Extension(state): Extension<ServerState>) -> Result<StatusCode> {
+    state.player.next().await.map_err(ApiError::player)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn status(Extension(state): Extension<ServerState>) -> Json<Status> {
+    Json(state.player.status())
+}
+
+/// Streams the transitions of the player as server-sent events
+pub async fn events(
+    Extension(state): Extension<ServerState>,
+) -> Sse<impl Stream<Item = std::result::Result<sse::Event, serde_json::Error>>> {
+    let events = stream::unfold(state.player.subscribe(), |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => return Some((sse::Event::default().json_data(event), events)),
+                // Slow clients miss some transitions but can always catch up using the status
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    // End the stream once taped shuts down, so that it doesn't hold up the graceful shutdown, or
+    // together with the player
+    let mut shutting_down = state.shutting_down.clone();
+    let ended = async move {
+        tokio::select! {
+            _ = async { while shutting_down.changed().await.is_ok() {} } => {}
+            _ = state.player.closed() => {}
+        }
+    };
+    Sse::new(events.take_until(ended)).keep_alive(KeepAlive::default())
+}
+
+pub async fn queue(Extension(state): Extension<ServerState>) -> Result<Json<Queue>> {
+    let queue = state.player.queue().await.map_err(ApiError::player)?;
+    Ok(Json(queue))
+}
+
+pub async fn enqueue(
+    Extension(state): Extension<ServerState>,
+    Json(request): Json<Play>,
+) -> Result<(StatusCode, Json<Created>)> {
+    let (source, order) = resolve(&state, request)?;
+    let id = state.player.enqueue(source, order).await;
+    let id = id.map_err(ApiError::player)?;
+    Ok((StatusCode::CREATED, Json(Created { id })))
+}
+
+pub async fn dequeue(
+    Path(id): Path<u64>,
+    Extension(state): Extension<ServerState>,
+) -> Result<StatusCode> {
+    let removed = state.player.remove(id).await.map_err(ApiError::player)?;
+    item_found(removed, id)
+}
+
+pub async fn clear_queue(Extension(state): Extension<ServerState>) -> Result<StatusCode> {
+    state.player.clear().await.map_err(ApiError::player)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+pub struct Move {
+    position: usize,
+}
+
+pub async fn move_item(
+    Path(id): Path<u64>,
+    Extension(state): Extension<ServerState>,
+    Json(request): Json<Move>,
+) -> Result<StatusCode> {
+    let moved = state.player.move_to(id, request.position).await;
+    item_found(moved.map_err(ApiError::player)?, id)
+}
+
+pub async fn play_item(
+    Path(id): Path<u64>,
+    Extension(state): Extension<ServerState>,
+) -> Result<StatusCode> {
+    let found = state.player.jump(id).await.map_err(ApiError::player)?;
+    item_found(found, id)
+}
+
+#[derive(Deserialize)]
+pub struct SetRepeat {
+    repeat: Repeat,
+}
+
+pub async fn set_repeat(
+    Extension(state): Extension<ServerState>,
+    Json(request): Json<SetRepeat>,
+) -> Result<StatusCode> {
+    let repeat = state.player.set_repeat(request.repeat).await;
+    repeat.map_err(ApiError::player)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn cassette_not_found(uuid: Uuid) -> ApiError {
+    ApiError::new(
+        ErrorCode::CassetteNotFound,
+        format!("no cassette with uuid {}", uuid),
+    )
+}
+
+fn item_found(found: bool, id: u64) -> Result<StatusCode> {
+    match found {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::new(
+            ErrorCode::QueueItemNotFound,
+            format!("no queue item with id {}", id),
+        )),
+    }
+}
+
+/// Looks up what a play request refers to in the catalog
+fn resolve(state: &ServerState, request: Play) -> Result<(Source, Order)> {
+    let catalog = state.catalog.load();
+    match request.target {
+        Target::Cassette(uuid) => {
+            let cassette = catalog
+                .cassettes
+                .get(&uuid)
+                .ok_or_else(|| cassette_not_found(uuid))?;
+            let (start, order) = match request.track {
+                Some(track) => (track_index(cassette, track)?, Order::InOrder),
+                None => (0, Order::Shuffled),
+            };
+            let source = Source::Cassette { uuid, start };
+            Ok((source, request.order.unwrap_or(order)))
+        }
+        Target::Song(id) => {
+            let song = find_song(&catalog.cassettes, &id).ok_or_else(|| {
+                ApiError::new(ErrorCode::SongNotFound, format!("no song with id {}", id))
+            })?;
+            let order = request.order.unwrap_or(Order::InOrder);
+            Ok((Source::Song(song), order))
+        }
     }
-    let cassette = &state.cassettes[&uuid];
-    println!("playing {}", &cassette.name);
-    let handle = std::process::Command::new(MPV)
-        .args(&["--no-video", "--shuffle", &cassette.yt_url])
-        .spawn()
-        .unwrap();
-    state.mpv_process = Some(handle);
-}
-
-pub async fn stop(Extension(state): Extension<Arc<RwLock<ServerState>>>) {
-    println!("stopping");
-    let mut state = state.write();
-    if let Some(mut handle) = state.mpv_process.take() {
-        handle.kill().expect("failed to kill mpv process");
+}
+
+/// Converts a 1-based track number into an index in the playlist of the cassette. The track count
+/// is only checked when the songs of the cassette are known.
+fn track_index(cassette: &Cassette, track: usize) -> Result<usize> {
+    let known = cassette.videos.is_empty() || track <= cassette.videos.len();
+    if track == 0 || !known {
+        return Err(ApiError::new(
+            ErrorCode::TrackNotFound,
+            format!("{} has no track {}", cassette.name, track),
+        ));
     }
+    Ok(track - 1)
 }
 
-pub async fn list(
-    Extension(state): Extension<Arc<RwLock<ServerState>>>,
-) -> Json<HashMap<Uuid, Cassette>> {
-    let state = state.read();
-    Json(state.cassettes.clone())
+fn find_song(cassettes: &Cassettes, id: &str) -> Option<Song> {
+    cassettes
+        .values()
+        .flat_map(|cassette| &cassette.videos)
+        .find(|song| song.id == id)
+        .cloned()
 }
 
-pub async fn fallback(
-    uri: Uri,
-) -> std::result::Result<(Headers<[(HeaderName, String); 1]>, &'static [u8]), StatusCode> {
+/// The loudness measured for a song so far, by the id of the source format it was measured in
+pub async fn loudness(
+    Path(id): Path<String>,
+    Extension(state): Extension<ServerState>,
+) -> Json<BTreeMap<String, Measurement>> {
+    Json(state.loudness.song(&id))
+}
+
+pub async fn downloads(Extension(state): Extension<ServerState>) -> Json<Vec<Job>> {
+    Json(state.downloads.jobs())
+}
+
+pub async fn download(
+    Path(id): Path<u64>,
+    Extension(state): Extension<ServerState>,
+) -> Result<Json<Job>> {
+    match state.downloads.job(id) {
+        Some(job) => Ok(Json(job)),
+        None => Err(download_not_found(id)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StartDownload {
+    cassette: Uuid,
+    /// Defaults to the configured profile
+    profile: Option<Profile>,
+    /// Defaults to the configured codec
+    codec: Option<Codec>,
+    /// Also exports the cassette as a single file with a chapter per song
+    tape: Option<TapeFormat>,
+    /// Also renders the cassette as the two sides of a real tape of this length, with a J-card
+    sides: Option<TapeLength>,
+}
+
+/// Queues a download of a cassette into the library. If the cassette is already being downloaded
+/// the existing job is returned instead.
+pub async fn start_download(
+    Extension(state): Extension<ServerState>,
+    Json(request): Json<StartDownload>,
+) -> Result<(StatusCode, Json<Job>)> {
+    let catalog = state.catalog.load();
+    let cassette = catalog
+        .cassettes
+        .get(&request.cassette)
+        .ok_or_else(|| cassette_not_found(request.cassette))?;
+    if let Some(profile) = request.profile {
+        let valid = profile.validate();
+        valid.map_err(|err| ApiError::new(ErrorCode::InvalidRequest, err))?;
+    }
+    match state.downloads.start(
+        cassette,
+        request.profile,
+        request.codec,
+        request.tape,
+        request.sides,
+    ) {
+        (job, true) => Ok((StatusCode::CREATED, Json(job))),
+        (job, false) => Ok((StatusCode::OK, Json(job))),
+    }
+}
+
+/// Cancels a queued or running download, or forgets a finished one
+pub async fn cancel_download(
+    Path(id): Path<u64>,
+    Extension(state): Extension<ServerState>,
+) -> Result<StatusCode> {
+    match state.downloads.cancel(id) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(download_not_found(id)),
+    }
+}
+
+fn download_not_found(id: u64) -> ApiError {
+    ApiError::new(
+        ErrorCode::DownloadNotFound,
+        format!("no download with id {}", id),
+    )
+}
+
+pub async fn refresh_status(Extension(state): Extension<ServerState>) -> Json<refresh::Status> {
+    Json(state.refresh.status())
+}
+
+/// Starts a catalog refresh, unless one is already running
+pub async fn refresh(Extension(state): Extension<ServerState>) -> Result<StatusCode> {
+    match state.refresh.trigger() {
+        true => Ok(StatusCode::ACCEPTED),
+        false => Err(ApiError::new(
+            ErrorCode::RefreshRunning,
+            "a refresh is already running",
+        )),
+    }
+}
+
+pub async fn fallback(uri: Uri) -> Response {
+    if uri.path() == "/api" || uri.path().starts_with("/api/") {
+        let message = format!("no endpoint at {}", uri.path());
+        return ApiError::new(ErrorCode::NotFound, message).into_response();
+    }
+
     let path = if uri.path() == "/" {
         "index.html"
     } else {
         &uri.path()[1..]
     };
-    let content_type = mime_guess::from_path(&path)
+    let content_type = mime_guess::from_path(path)
         .first_or_octet_stream()
         .essence_str()
         .to_owned();
-    let body = match ROOT.get_file(&path) {
+    let body = match ROOT.get_file(path) {
         Some(file) => file.contents(),
-        None => return Err(StatusCode::NOT_FOUND),
+        None => return StatusCode::NOT_FOUND.into_response(),
     };
     let headers = Headers([(http::header::CONTENT_TYPE, content_type)]);
-    Ok((headers, body))
+    (headers, body).into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use axum::body::HttpBody;
+    use serde_json::Value;
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::catalog::Catalog;
+    use crate::config::Config;
+
+    async fn json(response: Response) -> Value {
+        let mut body = response.into_body();
+        let mut bytes = vec![];
+        while let Some(chunk) = body.data().await {
+            bytes.extend(chunk.unwrap());
+        }
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn separate_cache_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            data_dir: dir.path().to_owned(),
+            ..Default::default()
+        };
+        let cassette = Cassette {
+            uuid: Uuid::from_u128(7),
+            name: "Nero".into(),
+            ..Default::default()
+        };
+        let uuid = cassette.uuid;
+        let cassettes = [(uuid, cassette)].into_iter().collect();
+        // Recently fetched, so that the catalog isn't refreshed during the test
+        let catalog = Catalog::new(cassettes, Default::default());
+        let snapshot_path = dir.path().join("catalog.json");
+        let (_shutdown, shutting_down) = watch::channel(());
+        let state = ServerState::spawn(&config, catalog, snapshot_path, shutting_down).unwrap();
+
+        // A query string that is just the key the cassette used to be cached under
+        let uri: Uri = format!("/api/v1/cassettes?{}", uuid).parse().unwrap();
+        let list = |state: ServerState, uri: Uri| {
+            let query = Query(catalog::Query::default());
+            super::list(query, uri, HeaderMap::new(), Extension(state))
+        };
+        let page = json(list(state.clone(), uri.clone()).await.unwrap()).await;
+        assert_eq!(page["total"], 1);
+
+        let response = super::cassette(Path(uuid), HeaderMap::new(), Extension(state.clone()));
+        let cassette = json(response.await.unwrap()).await;
+        assert_eq!(cassette["name"], "Nero");
+        assert!(cassette.get("total").is_none());
+
+        let page = json(list(state.clone(), uri).await.unwrap()).await;
+        assert_eq!(page["cassettes"][0]["name"], "Nero");
+    }
 }
@@ -0,0 +1,226 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::info;
+use serde::Serialize;
+
+use kasetophono::{Cassette, Song};
+
+use crate::audio::Codec;
+use crate::{persist, Result};
+
+/// The file the songs of a downloaded cassette are listed in, since the catalog usually doesn't
+/// know them
+const SONGS_FILE: &str = "songs.json";
+
+/// The cassettes downloaded for offline listening, each one in a directory under `Cassette.path`
+#[derive(Clone, Debug)]
+pub struct Library {
+    root: PathBuf,
+}
+
+/// A track of a cassette and where it can be played from
+#[derive(Clone, Debug, Serialize)]
+pub struct Track {
+    /// The 1-based track number
+    pub number: usize,
+    #[serde(flatten)]
+    pub song: Song,
+    #[serde(flatten)]
+    pub location: Location,
+    /// The gain in dB the player evens out the loudness of a streamed track with, if it has one
+    pub gain: Option<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "location", rename_all = "snake_case")]
+pub enum Location {
+    /// The track is in the library
+    Local { path: PathBuf },
+    /// The track has to be streamed
+    Remote { url: String },
+}
+
+impl Library {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// The directory the tracks of a cassette are stored in
+    pub fn dir(&self, cassette: &Cassette) -> PathBuf {
+        self.root.join(&cassette.path)
+    }
+
+    /// Remembers the songs of a cassette for when it is played back
+    pub fn save_songs(&self, cassette: &Cassette) -> Result<()> {
+        persist::save(&self.dir(cassette).join(SONGS_FILE), &cassette.videos)
+    }
+
+    /// The songs of a cassette, either from the catalog or as saved when it was downloaded
+    fn songs(&self, cassette: &Cassette) -> Vec<Song> {
+        if !cassette.videos.is_empty() {
+            return cassette.videos.clone();
+        }
+        let path = self.dir(cassette).join(SONGS_FILE);
+        match persist::load(&path) {
+            Ok(songs) => songs.unwrap_or_default(),
+            Err(err) => {
+                info!("ignoring {}: {}", path.display(), err);
+                vec![]
+            }
+        }
+    }
+
+    /// Lists the tracks of a cassette in order, preferring the copies in the library. Empty if the
+    /// songs of the cassette aren't known.
+    pub async fn tracks(&self, cassette: &Cassette) -> Vec<Track> {
+        let library = self.clone();
+        let cassette = cassette.clone();
+        let tracks = tokio::task::spawn_blocking(move || library.read_tracks(&cassette));
+        match tracks.await {
+            Ok(tracks) => tracks,
+            Err(err) => {
+                info!("failed to list tracks: {}", err);
+                vec![]
+            }
+        }
+    }
+
+    /// Like `tracks`, but reading the library directory and the saved songs in place
+    fn read_tracks(&self, cassette: &Cassette) -> Vec<Track> {
+        let songs = self.songs(cassette);
+        let dir = self.dir(cassette);
+        // Find tracks by their number, in case their title changed since they were downloaded
+        let files: Vec<String> = match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|name| Codec::from_path(Path::new(name)).is_some())
+                .collect(),
+            Err(_) => vec![],
+        };
+
+        let total = songs.len();
+        let tracks = songs.into_iter().enumerate().map(|(index, song)| {
+            let number = index + 1;
+            let prefix = track_prefix(number, total);
+            let location = match files.iter().find(|name| name.starts_with(&prefix)) {
+                Some(name) => Location::Local {
+                    path: dir.join(name),
+                },
+                None => Location::Remote {
+                    url: format!("https://www.youtube.com/watch?v={}", song.id),
+                },
+            };
+            Track {
+                number,
+                song,
+                location,
+                gain: None,
+            }
+        });
+        tracks.collect()
+    }
+}
+
+impl Track {
+    pub fn is_local(&self) -> bool {
+        matches!(self.location, Location::Local { .. })
+    }
+}
+
+/// The name of a track in the directory of its cassette. Track numbers are zero padded so that
+/// the tracks sort in order.
+pub fn track_file(track: usize, total: usize, song: &Song, codec: Codec) -> String {
+    let title = song.title.replace('/', "-");
+    let extension = codec.extension();
+    format!("{}{}.{}", track_prefix(track, total), title, extension)
+}
+
+fn track_prefix(track: usize, total: usize) -> String {
+    let width = total.to_string().len().max(2);
+    format!("{:0width$} - ", track, width = width)
+}
+
+/// Renders tracks as an extended M3U playlist
+pub fn playlist(tracks: &[Track]) -> String {
+    let mut playlist = String::from("#EXTM3U\n");
+    for track in tracks {
+        let duration = track.song.duration.map_or(-1, |duration| duration as i64);
+        let location = match &track.location {
+            Location::Local { path } => path.to_string_lossy(),
+            Location::Remote { url } => url.into(),
+        };
+        let title = track.song.title.replace('\n', " ");
+        let _ = write!(playlist, "#EXTINF:{},{}\n{}\n", duration, title, location);
+    }
+    playlist
+}
+
+/// Writes the playlist of a cassette for the player
+pub fn write_playlist(path: &Path, tracks: &[Track]) -> Result<()> {
+    persist::write(path, playlist(tracks).as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn song(id: &str, title: &str) -> Song {
+        Song {
+            id: id.into(),
+            title: title.into(),
+            duration: Some(200),
+        }
+    }
+
+    #[test]
+    fn track_files() {
+        let song = song("va-EudnxtAc", "AC/DC - Thunderstruck");
+        assert_eq!(
+            track_file(3, 12, &song, Codec::Mp3),
+            "03 - AC-DC - Thunderstruck.mp3"
+        );
+        assert_eq!(
+            track_file(3, 120, &song, Codec::Flac),
+            "003 - AC-DC - Thunderstruck.flac"
+        );
+    }
+
+    #[tokio::test]
+    async fn local_tracks() {
+        let root = tempfile::tempdir().unwrap();
+        let library = Library::new(root.path().to_owned());
+        let cassette = Cassette {
+            name: "Nero".into(),
+            safe_name: "Nero".into(),
+            path: "2019/01/cassettes/Nero".into(),
+            videos: vec![song("a", "First"), song("b", "Second")],
+            ..Default::default()
+        };
+        library.save_songs(&cassette).unwrap();
+        let renamed = song("b", "Second (Remastered)");
+        let path = library
+            .dir(&cassette)
+            .join(track_file(2, 2, &renamed, Codec::Opus));
+        fs::write(&path, b"").unwrap();
+
+        // The songs are read back from the library when the catalog doesn't know them
+        let cassette = Cassette {
+            videos: vec![],
+            ..cassette
+        };
+        let tracks = library.tracks(&cassette).await;
+        assert_eq!(tracks.len(), 2);
+        assert!(!tracks[0].is_local());
+        assert_eq!(tracks[1].location, Location::Local { path: path.clone() });
+
+        let expected = format!(
+            "#EXTM3U\n\
+             #EXTINF:200,First\nhttps://www.youtube.com/watch?v=a\n\
+             #EXTINF:200,Second\n{}\n",
+            path.display()
+        );
+        assert_eq!(playlist(&tracks), expected);
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use kasetophono::Song;
+
+use crate::audio::{Ffmpeg, LoudNorm, Profile};
+use crate::{persist, Result};
+
+/// How many songs can wait to be measured in the background
+const QUEUE_SIZE: usize = 256;
+
+/// The loudness measurements of songs, by video id and then by the format they were measured in
+type Songs = BTreeMap<String, BTreeMap<String, Measurement>>;
+
+/// Remembers the loudness of songs so that their audio only has to be analysed once. Measurements
+/// depend on the audio itself, so they are kept per source format of a video.
+pub struct Cache {
+    songs: Mutex<Songs>,
+    /// Where the measurements are saved whenever one is added
+    path: PathBuf,
+    /// Held while saving, so that saves land in the order the measurements were added
+    saving: tokio::sync::Mutex<()>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Measurement {
+    #[serde(flatten)]
+    pub loudness: LoudNorm,
+    /// The profile the target offset was measured for
+    pub profile: Profile,
+    pub measured_at: DateTime<Utc>,
+}
+
+impl Cache {
+    pub fn load(path: PathBuf) -> Self {
+        let songs = match persist::load::<Songs>(&path) {
+            Ok(songs) => songs.unwrap_or_default(),
+            Err(err) => {
+                info!("discarding saved loudness measurements: {}", err);
+                Songs::default()
+            }
+        };
+        Self {
+            songs: Mutex::new(songs),
+            path,
+            saving: Default::default(),
+        }
+    }
+
+    pub fn get(&self, song: &str, format: &str) -> Option<Measurement> {
+        let songs = self.songs.lock().unwrap();
+        songs.get(song)?.get(format).copied()
+    }
+
+    /// All measurements of a song, by format
+    pub fn song(&self, song: &str) -> BTreeMap<String, Measurement> {
+        let songs = self.songs.lock().unwrap();
+        songs.get(song).cloned().unwrap_or_default()
+    }
+
+    pub async fn insert(&self, song: &str, format: &str, measurement: Measurement) {
+        let _saving = self.saving.lock().await;
+        let songs = {
+            let mut songs = self.songs.lock().unwrap();
+            let formats = songs.entry(song.to_string()).or_default();
+            formats.insert(format.to_string(), measurement);
+            songs.clone()
+        };
+        let path = self.path.clone();
+        let saved = tokio::task::spawn_blocking(move || persist::save(&path, &songs)).await;
+        if let Err(err) = saved.map_err(Into::into).and_then(|saved| saved) {
+            info!("failed to save loudness measurements: {}", err);
+        }
+    }
+
+    /// Any measurement of a song. Formats of the same video barely differ in loudness, so this is
+    /// good enough for playback.
+    fn any(&self, song: &str) -> Option<Measurement> {
+        let songs = self.songs.lock().unwrap();
+        songs.get(song)?.values().next().copied()
+    }
+}
+
+impl Measurement {
+    pub fn new(loudness: LoudNorm, profile: Profile) -> Self {
+        Self {
+            loudness,
+            profile,
+            measured_at: Utc::now(),
+        }
+    }
+
+    /// The measurements to normalise to `profile` with. The input loudness doesn't depend on the
+    /// profile, but the offset is only known for the one that was measured for, and it is small
+    /// enough to be left out otherwise.
+    pub fn for_profile(&self, profile: Profile) -> LoudNorm {
+        match self.profile == profile {
+            true => self.loudness,
+            false => LoudNorm {
+                target_offset: 0.0,
+                ..self.loudness
+            },
+        }
+    }
+}
+
+/// A cheaply cloneable handle for looking up the gain of songs, which measures the ones that
+/// weren't measured yet in the background
+#[derive(Clone)]
+pub struct Handle {
+    cache: Arc<Cache>,
+    /// The loudness songs are brought to
+    profile: Profile,
+    songs: mpsc::Sender<Song>,
+    /// The songs that were queued since taped started. Songs that failed to be measured aren't
+    /// attempted again until a restart.
+    queued: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Handle {
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    /// The gain in dB to play a song with. If the song wasn't measured yet it is queued to be
+    /// measured and `None` is returned.
+    pub fn gain(&self, song: &Song) -> Option<f32> {
+        if let Some(measurement) = self.cache.any(&song.id) {
+            return Some(self.profile.gain(&measurement.loudness));
+        }
+        let mut queued = self.queued.lock().unwrap();
+        if queued.insert(song.id.clone()) && self.songs.try_send(song.clone()).is_err() {
+            // Try again next time the song is played
+            queued.remove(&song.id);
+        }
+        None
+    }
+}
+
+/// Spawns the task that measures streamed songs, one at a time
+pub fn spawn(cache: Arc<Cache>, ffmpeg: Ffmpeg, downloader: PathBuf, profile: Profile) -> Handle {
+    let (songs, receiver) = mpsc::channel(QUEUE_SIZE);
+    let measurer = Measurer {
+        cache: cache.clone(),
+        ffmpeg,
+        downloader,
+        profile,
+    };
+    tokio::spawn(measurer.run(receiver));
+    Handle {
+        cache,
+        profile,
+        songs,
+        queued: Arc::default(),
+    }
+}
+
+struct Measurer {
+    cache: Arc<Cache>,
+    ffmpeg: Ffmpeg,
+    /// The youtube-dl compatible executable used to find the audio stream of songs
+    downloader: PathBuf,
+    profile: Profile,
+}
+
+impl Measurer {
+    async fn run(self, mut songs: mpsc::Receiver<Song>) {
+        while let Some(song) = songs.recv().await {
+            if let Err(err) = self.measure(&song).await {
+                info!("failed to measure {}: {:#}", song.title, err);
+            }
+        }
+    }
+
+    async fn measure(&self, song: &Song) -> Result<()> {
+        let downloader = self.downloader.clone();
+        let stream = {
+            let song = song.clone();
+            tokio::task::spawn_blocking(move || song.audio_stream_with(downloader))
+        };
+        let stream = stream.await?.context("finding audio stream")?;
+        let stream = stream.ok_or_else(|| anyhow!("no audio only stream"))?;
+
+        info!("measuring {} in format {}", song.title, stream.format_id);
+        let duration = song.duration.map(Duration::from_secs);
+        // ffmpeg reads URLs just like files
+        let input = Path::new(&stream.url);
+        let loudness = self
+            .ffmpeg
+            .measure_loudness(input, self.profile, duration, |_| {});
+        let measurement = Measurement::new(loudness.await?, self.profile);
+        self.cache
+            .insert(&song.id, &stream.format_id, measurement)
+            .await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn measurements() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("loudness.json");
+        let cache = Cache::load(path.clone());
+        let loudness = LoudNorm {
+            input_i: -14.01,
+            input_tp: -0.21,
+            input_lra: 1.1,
+            input_thresh: -24.03,
+            target_offset: -0.4,
+        };
+        cache
+            .insert(
+                "va-EudnxtAc",
+                "251",
+                Measurement::new(loudness, Profile::Broadcast),
+            )
+            .await;
+        assert!(cache.get("va-EudnxtAc", "140").is_none());
+
+        // Measurements survive a restart
+        let cache = Cache::load(path);
+        let measurement = cache.get("va-EudnxtAc", "251").unwrap();
+        assert_eq!(measurement.for_profile(Profile::Broadcast), loudness);
+        assert_eq!(
+            measurement.for_profile(Profile::Streaming).target_offset,
+            0.0
+        );
+        assert_eq!(cache.song("va-EudnxtAc").len(), 1);
+        assert!(cache.song("a").is_empty());
+    }
+}
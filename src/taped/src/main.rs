@@ -1,160 +1,219 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::process::Child;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
 
+use arc_swap::ArcSwap;
 use axum::extract::Extension;
-use axum::routing::get;
+use axum::middleware;
+use axum::routing::{any, delete, get, post, put};
 use axum::Router;
-use futures::stream::{self, StreamExt, TryStreamExt};
-use futures::TryFutureExt;
-use log::{debug, info};
-use parking_lot::RwLock;
+use clap::Parser;
+use futures::future;
+use log::info;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
 use tower_http::compression::CompressionLayer;
-use uuid::Uuid;
-
-use kasetophono::{scrape::blogger, Cassette, Category, Subcategory};
 
+use kasetophono::upstream::Upstream;
+
+use crate::audio::Ffmpeg;
+use crate::cache::ResponseCache;
+use crate::catalog::Catalog;
+use crate::config::{Args, Config};
+use crate::cover::Covers;
+use crate::library::Library;
+use crate::metrics::Metrics;
+
+mod audio;
+mod cache;
+mod catalog;
+mod config;
+mod cover;
+mod downloads;
+mod error;
 mod handlers;
+mod library;
+mod loudness;
+mod metrics;
+mod persist;
+mod player;
+mod queue;
+mod refresh;
+mod sides;
+mod tags;
 
 type Result<T> = std::result::Result<T, anyhow::Error>;
 
-async fn subcategories(categories: &[Category]) -> Result<Vec<Subcategory>> {
-    let mut responses = stream::iter(categories)
-        .map(|c| reqwest::get(&c.url).and_then(|r| r.text()))
-        .buffer_unordered(5)
-        // Workaround for rust-lang/rust#89976
-        .boxed();
-
-    let mut subcategories = vec![];
-    while let Some(response) = responses.try_next().await? {
-        let subs = kasetophono::scrape::subcategory::scrape_subcategories(&response)?;
-        subcategories.extend(subs);
-    }
-    Ok(subcategories)
+#[derive(Clone)]
+pub struct ServerState {
+    /// The latest snapshot of the catalog, swapped in as a whole on every refresh
+    catalog: Arc<ArcSwap<Catalog>>,
+    /// The serialized responses of the catalog endpoints
+    responses: Arc<ResponseCache>,
+    covers: Arc<Covers>,
+    loudness: Arc<loudness::Cache>,
+    metrics: Arc<Metrics>,
+    player: player::Handle,
+    refresh: refresh::Handle,
+    downloads: downloads::Handle,
+    /// Closed once taped starts shutting down
+    shutting_down: watch::Receiver<()>,
 }
 
-async fn cassettes(subcategories: &[Subcategory]) -> Result<HashMap<Uuid, Cassette>> {
-    const PAGE_SIZE: usize = 25;
-    let mut responses = stream::iter((1..).step_by(PAGE_SIZE))
-        .map(|page| {
-            reqwest::get(format!(
-                "https://www.kasetophono.com/feeds/posts/default?alt=json\
-                &start-index={}\
-                &max-results={}",
-                page, PAGE_SIZE,
-            ))
-            .and_then(|r| r.text())
+impl ServerState {
+    /// Spawns the tasks behind the API, starting from the given catalog
+    fn spawn(
+        config: &Config,
+        catalog: Catalog,
+        snapshot_path: PathBuf,
+        shutting_down: watch::Receiver<()>,
+    ) -> Result<Self> {
+        let catalog = Arc::new(ArcSwap::from_pointee(catalog));
+        let metrics = Arc::new(Metrics::new()?);
+        let library = Library::new(config.library());
+        let loudness = loudness::Cache::load(config.data_dir.join("loudness.json"));
+        let loudness = Arc::new(loudness);
+        let measurer = loudness::spawn(
+            loudness.clone(),
+            Ffmpeg::new(&config.audio, config.work_dir()),
+            config.downloads.downloader.clone(),
+            config.audio.profile,
+        );
+        let player = player::spawn(
+            catalog.clone(),
+            metrics.clone(),
+            library.clone(),
+            measurer,
+            &config.data_dir,
+            config.player.clone(),
+        );
+        let upstream = Upstream::new(&config.upstream.base_url);
+        let client = refresh::Client::new(upstream, config.refresh.clone(), metrics.clone())?;
+        let refresh = refresh::spawn(catalog.clone(), snapshot_path, client);
+
+        let covers = Covers::new(
+            config.data_dir.join("covers"),
+            &config.refresh,
+            metrics.clone(),
+        )?;
+        let covers = Arc::new(covers);
+        let downloads = downloads::spawn(
+            catalog.clone(),
+            covers.clone(),
+            library,
+            loudness.clone(),
+            config,
+        );
+
+        Ok(Self {
+            catalog,
+            responses: Arc::default(),
+            covers,
+            loudness,
+            metrics,
+            player,
+            refresh,
+            downloads,
+            shutting_down,
         })
-        .buffer_unordered(5);
-
-    let mut cassettes = HashMap::new();
-    while let Some(mut response) = responses.try_next().await? {
-        let document: blogger::Document = match serde_json::from_str(&response) {
-            Ok(document) => document,
-            Err(_) => {
-                // The website suddenly started serving responses with invalid JSON where two
-                // objects are separated by two commas instead of one. Probably a bug somewhere in
-                // Google. Workaround by retrying the deserialization after replacing all double
-                // commas with single ones
-                response = response.replace(",,", ",");
-                serde_json::from_str(&response).unwrap()
-            }
-        };
-
-        let mut empty = true;
-        for entry in document.feed.entry {
-            if let Some(mut cassette) = Cassette::try_from_entry(entry) {
-                empty = false;
-                cassette.fill_subcategories(subcategories);
-                cassettes.insert(cassette.uuid, cassette);
-            }
-        }
-        if empty {
-            break;
-        }
     }
-
-    let total = cassettes.len();
-    debug!("fetched {} cassettes", total);
-
-    // let mut i = 0;
-    // cassettes.retain(move |_uuid, cassette| {
-    //     i += 1;
-    //     debug!("fetching songs ({}/{}): {:?}", i, total, &cassette.name);
-    //     match cassette.fill_songs() {
-    //         Ok(()) => true,
-    //         Err(e) => {
-    //             debug!("discarding cassette: {:?}: {}", &cassette.name, e);
-    //             false
-    //         }
-    //     }
-    // });
-
-    Ok(cassettes)
-}
-
-async fn load_cassettes() -> Result<HashMap<Uuid, Cassette>> {
-    let body = reqwest::get("https://www.kasetophono.com")
-        .await?
-        .text()
-        .await?;
-
-    let categories = kasetophono::scrape::category::scrape_categories(&body)?;
-
-    let subcategories = subcategories(&categories).await?;
-    let cassettes = cassettes(&subcategories).await?;
-    Ok(cassettes)
-}
-
-async fn refresh_loop(state: Arc<RwLock<ServerState>>) {
-    loop {
-        info!("loading cassettes from upstream");
-        match load_cassettes().await {
-            Ok(cassettes) => {
-                {
-                    state.write().cassettes = cassettes;
-                }
-                // Refresh once a day
-                tokio::time::sleep(Duration::from_secs(24 * 60 * 60)).await;
-            }
-            Err(err) => {
-                info!("failed to get cassettes from upstream: {}", err);
-                tokio::time::sleep(Duration::from_secs(30)).await;
-            }
-        }
-    }
-}
-
-#[derive(Default)]
-pub struct ServerState {
-    cassettes: HashMap<Uuid, Cassette>,
-    mpv_process: Option<Child>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
+    let config = Config::load(Args::parse())?;
 
     info!("setting up http server");
-    let server_state = Arc::new(RwLock::new(ServerState::default()));
-
-    tokio::spawn(refresh_loop(server_state.clone()));
+    let snapshot_path = config.data_dir.join("catalog.json");
+    let catalog = match Catalog::load(&snapshot_path) {
+        Ok(Some(catalog)) => {
+            info!("loaded {} cassettes from snapshot", catalog.cassettes.len());
+            catalog
+        }
+        Ok(None) => Catalog::default(),
+        Err(err) => {
+            info!("discarding catalog snapshot: {}", err);
+            Catalog::default()
+        }
+    };
+
+    let (shutdown, shutting_down) = watch::channel(());
+    let server_state = ServerState::spawn(&config, catalog, snapshot_path, shutting_down.clone())?;
+    let player = server_state.player.clone();
+
+    let api = Router::new()
+        .route("/cassettes", get(handlers::list))
+        .route("/cassettes/:uuid", get(handlers::cassette))
+        .route("/cassettes/:uuid/cover", get(handlers::cover))
+        .route("/logo", get(handlers::logo))
+        .route("/player", get(handlers::status))
+        .route("/player/play", post(handlers::play))
+        .route("/player/stop", post(handlers::stop))
+        .route("/player/next", post(handlers::next))
+        .route("/songs/:id/loudness", get(handlers::loudness))
+        .route("/events", get(handlers::events))
+        .route(
+            "/queue",
+            get(handlers::queue)
+                .post(handlers::enqueue)
+                .delete(handlers::clear_queue),
+        )
+        .route("/queue/repeat", put(handlers::set_repeat))
+        .route("/queue/:id", delete(handlers::dequeue))
+        .route("/queue/:id/move", post(handlers::move_item))
+        .route("/queue/:id/play", post(handlers::play_item))
+        .route(
+            "/downloads",
+            get(handlers::downloads).post(handlers::start_download),
+        )
+        .route(
+            "/downloads/:id",
+            get(handlers::download).delete(handlers::cancel_download),
+        )
+        .route(
+            "/refresh",
+            get(handlers::refresh_status).post(handlers::refresh),
+        );
 
     let app = Router::new()
-        .route("/api/play/:uuid", get(handlers::play))
-        .route("/api/stop", get(handlers::stop))
-        .route("/api/cassettes", get(handlers::list))
+        .nest("/api/v1", api)
+        .route("/metrics", get(metrics::handler))
+        .route_layer(middleware::from_fn(metrics::track))
         .layer(CompressionLayer::new())
         .layer(Extension(server_state))
-        .fallback(get(handlers::fallback));
+        .fallback(any(handlers::fallback));
+
+    let terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        shutdown_signal(terminate).await;
+        drop(shutdown);
+    });
+
+    let mut servers = vec![];
+    for addr in &config.listen {
+        info!("listening on {}", addr);
+        let mut shutting_down = shutting_down.clone();
+        let server = axum::Server::try_bind(addr)?
+            .serve(app.clone().into_make_service())
+            .with_graceful_shutdown(async move { while shutting_down.changed().await.is_ok() {} });
+        servers.push(server);
+    }
+    let served = future::try_join_all(servers).await;
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3030));
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .expect("failed to bind socket");
+    // The servers drained their connections, so no request can reach the player anymore
+    if let Err(err) = player.shutdown().await {
+        info!("failed to shut down player: {}", err);
+    }
+    served?;
 
+    info!("shut down");
     Ok(())
 }
+
+/// Resolves when taped is asked to terminate
+async fn shutdown_signal(mut terminate: Signal) {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+        _ = terminate.recv() => info!("received SIGTERM"),
+    }
+}
@@ -0,0 +1,238 @@
+use std::time::{Duration, Instant};
+
+use axum::extract::{Extension, MatchedPath};
+use axum::http::{Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{Headers, IntoResponse, Response};
+use log::info;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::{Result, ServerState};
+
+/// The buckets of the upstream request and refresh durations, in seconds
+const SLOW_BUCKETS: &[f64] = &[
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// The metrics taped exports to Prometheus
+pub struct Metrics {
+    registry: Registry,
+    /// Finished refreshes by outcome: `success`, `partial` or `failure`
+    pub refreshes: IntCounterVec,
+    pub refresh_duration: Histogram,
+    /// Upstream requests by phase and outcome. Every retry counts as a request.
+    pub upstream_requests: IntCounterVec,
+    pub upstream_duration: HistogramVec,
+    pub cassettes: IntGauge,
+    /// When the catalog was last fetched from upstream, as a Unix timestamp
+    pub catalog_fetched: IntGauge,
+    pub http_requests: IntCounterVec,
+    pub http_duration: HistogramVec,
+    /// Set to 1 for the state the player is in and to 0 for the others
+    pub player_state: IntGaugeVec,
+    /// Seconds spent playing each cassette. Songs played on their own are counted under an empty
+    /// cassette label.
+    pub playback: IntCounterVec,
+    pub player_restarts: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("taped".into()), None)?;
+
+        let refreshes = IntCounterVec::new(
+            Opts::new("refreshes_total", "Finished catalog refreshes"),
+            &["outcome"],
+        )?;
+        let refresh_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "refresh_duration_seconds",
+                "How long catalog refreshes took",
+            )
+            .buckets(SLOW_BUCKETS.to_vec()),
+        )?;
+        let upstream_requests = IntCounterVec::new(
+            Opts::new("upstream_requests_total", "Requests made to upstream"),
+            &["phase", "outcome"],
+        )?;
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "How long requests to upstream took",
+            )
+            .buckets(SLOW_BUCKETS.to_vec()),
+            &["phase"],
+        )?;
+        let cassettes = IntGauge::new("catalog_cassettes", "Cassettes in the catalog")?;
+        let catalog_fetched = IntGauge::new(
+            "catalog_fetched_timestamp_seconds",
+            "When the catalog was last fetched from upstream",
+        )?;
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests served"),
+            &["route", "method", "status"],
+        )?;
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "How long HTTP requests took to respond to",
+            ),
+            &["route", "method"],
+        )?;
+        let player_state = IntGaugeVec::new(
+            Opts::new("player_state", "The state the player is in"),
+            &["state"],
+        )?;
+        let playback = IntCounterVec::new(
+            Opts::new("playback_seconds_total", "Seconds spent playing"),
+            &["cassette"],
+        )?;
+        let player_restarts = IntCounter::new(
+            "player_restarts_total",
+            "Player processes restarted after a crash",
+        )?;
+
+        registry.register(Box::new(refreshes.clone()))?;
+        registry.register(Box::new(refresh_duration.clone()))?;
+        registry.register(Box::new(upstream_requests.clone()))?;
+        registry.register(Box::new(upstream_duration.clone()))?;
+        registry.register(Box::new(cassettes.clone()))?;
+        registry.register(Box::new(catalog_fetched.clone()))?;
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_duration.clone()))?;
+        registry.register(Box::new(player_state.clone()))?;
+        registry.register(Box::new(playback.clone()))?;
+        registry.register(Box::new(player_restarts.clone()))?;
+
+        let metrics = Self {
+            registry,
+            refreshes,
+            refresh_duration,
+            upstream_requests,
+            upstream_duration,
+            cassettes,
+            catalog_fetched,
+            http_requests,
+            http_duration,
+            player_state,
+            playback,
+            player_restarts,
+        };
+        metrics.set_player_state(PlayerState::Idle);
+        Ok(metrics)
+    }
+
+    pub fn upstream_request(&self, phase: &str, success: bool, duration: Duration) {
+        let outcome = if success { "success" } else { "failure" };
+        self.upstream_requests
+            .with_label_values(&[phase, outcome])
+            .inc();
+        self.upstream_duration
+            .with_label_values(&[phase])
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn set_player_state(&self, state: PlayerState) {
+        for other in [
+            PlayerState::Idle,
+            PlayerState::Playing,
+            PlayerState::Restarting,
+        ] {
+            self.player_state
+                .with_label_values(&[other.as_str()])
+                .set((other == state) as i64);
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlayerState {
+    Idle,
+    Playing,
+    /// Waiting to restart a crashed player process
+    Restarting,
+}
+
+impl PlayerState {
+    fn as_str(self) -> &'static str {
+        match self {
+            PlayerState::Idle => "idle",
+            PlayerState::Playing => "playing",
+            PlayerState::Restarting => "restarting",
+        }
+    }
+}
+
+/// Records the count and latency of the requests to every route
+pub async fn track<B>(req: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let route = match req.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_string(),
+        None => req.uri().path().to_string(),
+    };
+    let method = req.method().clone();
+    let state = req.extensions().get::<ServerState>().cloned();
+
+    let response = next.run(req).await;
+
+    if let Some(state) = state {
+        let metrics = &state.metrics;
+        let status = response.status();
+        metrics
+            .http_requests
+            .with_label_values(&[&route, method.as_str(), status.as_str()])
+            .inc();
+        metrics
+            .http_duration
+            .with_label_values(&[&route, method.as_str()])
+            .observe(start.elapsed().as_secs_f64());
+    }
+    response
+}
+
+pub async fn handler(Extension(state): Extension<ServerState>) -> impl IntoResponse {
+    let catalog = state.catalog.load();
+    let metrics = &state.metrics;
+    metrics.cassettes.set(catalog.cassettes.len() as i64);
+    if let Some(fetched_at) = catalog.fetched_at {
+        metrics.catalog_fetched.set(fetched_at.timestamp());
+    }
+
+    match metrics.encode() {
+        Ok(body) => {
+            let content_type = TextEncoder::new().format_type().to_string();
+            Ok((Headers([("content-type", content_type)]), body))
+        }
+        Err(err) => {
+            info!("failed to encode metrics: {}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let metrics = Metrics::new().unwrap();
+        metrics.refreshes.with_label_values(&["success"]).inc();
+        metrics.set_player_state(PlayerState::Playing);
+
+        let text = String::from_utf8(metrics.encode().unwrap()).unwrap();
+        assert!(text.contains("taped_refreshes_total{outcome=\"success\"} 1"));
+        assert!(text.contains("taped_player_state{state=\"playing\"} 1"));
+        assert!(text.contains("taped_player_state{state=\"idle\"} 0"));
+    }
+}
@@ -0,0 +1,60 @@
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tempfile::NamedTempFile;
+
+use crate::Result;
+
+/// Returns the directory taped keeps its state in
+pub fn state_dir() -> PathBuf {
+    // Set by systemd for units with a StateDirectory= setting
+    if let Some(dirs) = env::var_os("STATE_DIRECTORY") {
+        if let Some(dir) = env::split_paths(&dirs).next() {
+            return dir;
+        }
+    }
+    if let Some(dir) = env::var_os("XDG_STATE_HOME") {
+        return PathBuf::from(dir).join("taped");
+    }
+    match env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".local/state/taped"),
+        None => PathBuf::from(".taped"),
+    }
+}
+
+/// Atomically replaces the contents of `path` with the JSON representation of `value`
+pub fn save<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+
+    let file = NamedTempFile::new_in(dir)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    writer.into_inner()?.persist(path)?;
+    Ok(())
+}
+
+/// Atomically replaces the contents of `path` with `contents`
+pub fn write(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+
+    let mut file = NamedTempFile::new_in(dir)?;
+    file.write_all(contents)?;
+    file.persist(path)?;
+    Ok(())
+}
+
+/// Reads back a value previously written with `save`, if there is one
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,684 @@
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use arc_swap::ArcSwap;
+use log::info;
+use serde::Serialize;
+use tokio::process::{Child, Command};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::Instant;
+
+use crate::catalog::Catalog;
+use crate::config::{self, Loudness};
+use crate::library::{self, Library, Location, Track};
+use crate::loudness;
+use crate::metrics::{Metrics, PlayerState};
+use crate::persist;
+use crate::queue::{Item, Order, Queue, Repeat, Source};
+use crate::Result;
+
+type Reply<T> = oneshot::Sender<Result<T>>;
+
+/// The requests the player task understands. Each one carries a channel for the result.
+enum Request {
+    Play(Source, Order, Reply<u64>),
+    Enqueue(Source, Order, Reply<u64>),
+    Remove(u64, Reply<bool>),
+    Move(u64, usize, Reply<bool>),
+    Jump(u64, Reply<bool>),
+    SetRepeat(Repeat, Reply<()>),
+    Next(Reply<()>),
+    Stop(Reply<()>),
+    Clear(Reply<()>),
+    Queue(Reply<Queue>),
+    Shutdown(Reply<()>),
+}
+
+/// A cheaply cloneable handle for sending requests to the player task
+#[derive(Clone)]
+pub struct Handle {
+    requests: mpsc::Sender<Request>,
+    status: watch::Receiver<Status>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Handle {
+    /// Returns what the player is doing right now
+    pub fn status(&self) -> Status {
+        self.status.borrow().clone()
+    }
+
+    /// Subscribes to the transitions of the player
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Puts a source right after the current queue item and starts playing it
+    pub async fn play(&self, source: Source, order: Order) -> Result<u64> {
+        self.request(|reply| Request::Play(source, order, reply))
+            .await
+    }
+
+    /// Appends a source to the queue, starting playback if the player is idle
+    pub async fn enqueue(&self, source: Source, order: Order) -> Result<u64> {
+        self.request(|reply| Request::Enqueue(source, order, reply))
+            .await
+    }
+
+    pub async fn remove(&self, id: u64) -> Result<bool> {
+        self.request(|reply| Request::Remove(id, reply)).await
+    }
+
+    pub async fn move_to(&self, id: u64, position: usize) -> Result<bool> {
+        self.request(|reply| Request::Move(id, position, reply))
+            .await
+    }
+
+    pub async fn jump(&self, id: u64) -> Result<bool> {
+        self.request(|reply| Request::Jump(id, reply)).await
+    }
+
+    pub async fn set_repeat(&self, repeat: Repeat) -> Result<()> {
+        self.request(|reply| Request::SetRepeat(repeat, reply))
+            .await
+    }
+
+    pub async fn next(&self) -> Result<()> {
+        self.request(Request::Next).await
+    }
+
+    pub async fn stop(&self) -> Result<()> {
+        self.request(Request::Stop).await
+    }
+
+    pub async fn clear(&self) -> Result<()> {
+        self.request(Request::Clear).await
+    }
+
+    pub async fn queue(&self) -> Result<Queue> {
+        self.request(Request::Queue).await
+    }
+
+    /// Stops playback, saves the queue and terminates the player task
+    pub async fn shutdown(&self) -> Result<()> {
+        self.request(Request::Shutdown).await
+    }
+
+    /// Resolves once the player task has terminated
+    pub async fn closed(&self) {
+        let mut status = self.status.clone();
+        while status.changed().await.is_ok() {}
+    }
+
+    async fn request<T>(&self, request: impl FnOnce(Reply<T>) -> Request) -> Result<T> {
+        let (reply, result) = oneshot::channel();
+        self.requests
+            .send(request(reply))
+            .await
+            .map_err(|_| anyhow!("player task is gone"))?;
+        result.await.map_err(|_| anyhow!("player task is gone"))?
+    }
+}
+
+/// Spawns the task that owns the play queue and the player process
+pub fn spawn(
+    catalog: Arc<ArcSwap<Catalog>>,
+    metrics: Arc<Metrics>,
+    library: Library,
+    loudness: loudness::Handle,
+    state_dir: &Path,
+    config: config::Player,
+) -> Handle {
+    let queue_path = state_dir.join("queue.json");
+    let playlist_path = state_dir.join("playlist.m3u");
+    let queue = match persist::load::<Queue>(&queue_path) {
+        Ok(Some(mut queue)) => {
+            queue.restore();
+            queue
+        }
+        Ok(None) => Queue::default(),
+        Err(err) => {
+            info!("discarding saved queue: {}", err);
+            Queue::default()
+        }
+    };
+
+    let (requests, receiver) = mpsc::channel(16);
+    let (status, status_receiver) = watch::channel(Status::default());
+    let (events, _) = broadcast::channel(64);
+    let player = Player {
+        config,
+        catalog,
+        metrics,
+        library,
+        loudness,
+        queue,
+        queue_path,
+        playlist_path,
+        tracks: vec![],
+        process: None,
+        playing_since: None,
+        restarts: 0,
+        restart_at: None,
+        failures: 0,
+        last_exit: None,
+        status,
+        events: events.clone(),
+    };
+    tokio::spawn(player.run(receiver));
+    Handle {
+        requests,
+        status: status_receiver,
+        events,
+    }
+}
+
+/// Why the player process exited on its own
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Exit {
+    /// The player went through its whole playlist
+    Finished,
+    /// The player could not play anything from its playlist, e.g. because the videos are blocked
+    Unplayable,
+    /// The player exited with an unexpected error or was killed by a signal
+    Crashed {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+}
+
+impl From<io::Result<ExitStatus>> for Exit {
+    fn from(status: io::Result<ExitStatus>) -> Self {
+        // See the EXIT CODES section of mpv(1)
+        match status {
+            Ok(status) => match status.code() {
+                Some(0) | Some(3) => Exit::Finished,
+                Some(2) => Exit::Unplayable,
+                code => Exit::Crashed {
+                    code,
+                    signal: status.signal(),
+                },
+            },
+            Err(_) => Exit::Crashed {
+                code: None,
+                signal: None,
+            },
+        }
+    }
+}
+
+/// A snapshot of what the player is doing
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Status {
+    /// The queue item that is playing or about to be restarted
+    pub playing: Option<Item>,
+    /// The process id of the player process
+    pub pid: Option<u32>,
+    /// How many times the current item has been restarted after a crash
+    pub restarts: u32,
+    /// How the previous player process exited
+    pub last_exit: Option<Exit>,
+    /// The tracks of the cassette that is playing and whether they are played from the library
+    pub tracks: Vec<Track>,
+}
+
+/// The transitions of the player, as published to clients
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Started { item: Item, pid: Option<u32> },
+    Exited { item: Option<Item>, exit: Exit },
+    Restarting { item: Item, attempt: u32 },
+    Skipped { item: Item },
+    Idle,
+}
+
+struct Player {
+    config: config::Player,
+    catalog: Arc<ArcSwap<Catalog>>,
+    metrics: Arc<Metrics>,
+    library: Library,
+    loudness: loudness::Handle,
+    queue: Queue,
+    /// Where the queue is saved on shutdown
+    queue_path: PathBuf,
+    /// Where the playlist of cassettes with tracks in the library is written for the player
+    playlist_path: PathBuf,
+    /// The tracks of the cassette that is playing, if they are known
+    tracks: Vec<Track>,
+    process: Option<Child>,
+    /// When the player process started and the cassette label its playback time is counted under
+    playing_since: Option<(Instant, String)>,
+    /// How many times the current item has been restarted after a crash
+    restarts: u32,
+    /// When to restart the current item after a crash
+    restart_at: Option<Instant>,
+    /// How many queue items in a row failed to play
+    failures: usize,
+    last_exit: Option<Exit>,
+    status: watch::Sender<Status>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Player {
+    async fn run(mut self, mut requests: mpsc::Receiver<Request>) {
+        loop {
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(Request::Shutdown(reply)) => {
+                        let _ = reply.send(self.shutdown().await);
+                        break;
+                    }
+                    Some(request) => self.handle(request).await,
+                    None => break,
+                },
+                status = wait(&mut self.process) => {
+                    self.process = None;
+                    self.count_playback();
+                    self.exited(Exit::from(status)).await;
+                }
+                () = restart(self.restart_at) => {
+                    self.restart_at = None;
+                    self.spawn_current().await;
+                }
+            }
+        }
+    }
+
+    /// Decides what to play next after the player process exited on its own
+    async fn exited(&mut self, exit: Exit) {
+        let item = self.queue.current().cloned();
+        info!("player exited: {:?}", exit);
+        self.last_exit = Some(exit.clone());
+        self.publish(Event::Exited {
+            item: item.clone(),
+            exit: exit.clone(),
+        });
+
+        match (exit, item) {
+            (Exit::Finished, _) => {
+                self.failures = 0;
+                self.restarts = 0;
+                self.queue.advance();
+                self.spawn_current().await;
+            }
+            (Exit::Crashed { .. }, Some(item)) if self.restarts < self.config.max_restarts => {
+                self.restarts += 1;
+                self.metrics.player_restarts.inc();
+                self.restart_at = Some(Instant::now() + self.config.restart_delay);
+                self.publish(Event::Restarting {
+                    item,
+                    attempt: self.restarts,
+                });
+            }
+            (Exit::Crashed { .. } | Exit::Unplayable, item) => {
+                if let Some(item) = item {
+                    self.publish(Event::Skipped { item });
+                }
+                self.restarts = 0;
+                self.failures += 1;
+                // Give up once every item of the queue failed in a row
+                if self.failures >= self.queue.len() {
+                    info!("no playable item left in the queue");
+                    self.failures = 0;
+                    self.queue.stop();
+                } else {
+                    self.queue.skip();
+                }
+                self.spawn_current().await;
+            }
+        }
+    }
+
+    async fn handle(&mut self, request: Request) {
+        // A failed send means the requester went away, in which case nobody cares about the result
+        match request {
+            Request::Play(source, order, reply) => {
+                let id = self.queue.push_next(source, order);
+                self.queue.jump(id);
+                let _ = reply.send(self.play_current().await.map(|()| id));
+            }
+            Request::Enqueue(source, order, reply) => {
+                let id = self.queue.push(source, order);
+                let result = if self.process.is_none() {
+                    self.queue.jump(id);
+                    self.play_current().await
+                } else {
+                    Ok(())
+                };
+                let _ = reply.send(result.map(|()| id));
+            }
+            Request::Remove(id, reply) => {
+                let was_current = self.queue.current().map(|item| item.id) == Some(id);
+                let removed = self.queue.remove(id).is_some();
+                let result = if was_current {
+                    self.play_current().await
+                } else {
+                    Ok(())
+                };
+                let _ = reply.send(result.map(|()| removed));
+            }
+            Request::Move(id, position, reply) => {
+                let _ = reply.send(Ok(self.queue.move_to(id, position)));
+            }
+            Request::Jump(id, reply) => {
+                let result = if self.queue.jump(id) {
+                    self.play_current().await.map(|()| true)
+                } else {
+                    Ok(false)
+                };
+                let _ = reply.send(result);
+            }
+            Request::SetRepeat(repeat, reply) => {
+                self.queue.set_repeat(repeat);
+                let _ = reply.send(Ok(()));
+            }
+            Request::Next(reply) => {
+                self.queue.skip();
+                let _ = reply.send(self.play_current().await);
+            }
+            Request::Stop(reply) => {
+                info!("stopping");
+                self.queue.stop();
+                let _ = reply.send(self.play_current().await);
+            }
+            Request::Clear(reply) => {
+                self.queue.clear();
+                let _ = reply.send(self.play_current().await);
+            }
+            Request::Queue(reply) => {
+                let _ = reply.send(Ok(self.queue.clone()));
+            }
+            Request::Shutdown(_) => unreachable!("handled by the run loop"),
+        }
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        info!("shutting down player");
+        if let Some(mut process) = self.process.take() {
+            process.kill().await?;
+        }
+        self.count_playback();
+        self.restart_at = None;
+        self.publish(Event::Idle);
+        persist::save(&self.queue_path, &self.queue)
+    }
+
+    /// Stops whatever is currently playing and starts playing the current item of the queue, if
+    /// any
+    async fn play_current(&mut self) -> Result<()> {
+        if let Some(mut process) = self.process.take() {
+            process.kill().await?;
+        }
+        self.count_playback();
+        self.restarts = 0;
+        self.restart_at = None;
+        self.failures = 0;
+        self.try_spawn_current().await
+    }
+
+    /// Like `try_spawn_current` but for when there is nobody to report the error to
+    async fn spawn_current(&mut self) {
+        if let Err(err) = self.try_spawn_current().await {
+            info!("failed to start player: {}", err);
+        }
+    }
+
+    /// Spawns a player process for the current item of the queue
+    async fn try_spawn_current(&mut self) -> Result<()> {
+        let catalog = self.catalog.load_full();
+        let mut command = Command::new(&self.config.path);
+        // Never let the player outlive taped, even if the player task goes away abruptly
+        command.kill_on_drop(true).arg("--no-video");
+        command.args(&self.config.args);
+        self.tracks = vec![];
+        let (targets, order, label) = loop {
+            let item = match self.queue.current() {
+                Some(item) => item,
+                None => {
+                    self.publish(Event::Idle);
+                    return Ok(());
+                }
+            };
+            match &item.source {
+                Source::Cassette { uuid, start } => match catalog.cassettes.get(uuid) {
+                    Some(cassette) => {
+                        let (order, label) = (item.order, uuid.to_string());
+                        // The start track is meaningless once the player shuffles the tracks
+                        if order == Order::InOrder {
+                            info!("playing {} from track {}", &cassette.name, start + 1);
+                            command.arg(format!("--playlist-start={}", start));
+                        } else {
+                            info!("playing {} shuffled", &cassette.name);
+                        }
+                        let mut tracks = self.library.tracks(cassette).await;
+                        let filters = self.filters(&mut tracks);
+                        self.tracks = tracks;
+                        // Options of single tracks only work for the files on the command line
+                        if filters.iter().any(Option::is_some) {
+                            let filtered = filters.iter().flatten().count();
+                            info!("{} of {} tracks are filtered", filtered, filters.len());
+                            break (track_args(&self.tracks, &filters), order, label);
+                        }
+                        // Only stream the tracks that aren't in the library
+                        if self.tracks.iter().any(Track::is_local) {
+                            library::write_playlist(&self.playlist_path, &self.tracks)?;
+                            let local = self.tracks.iter().filter(|track| track.is_local());
+                            info!(
+                                "{} of {} tracks are local",
+                                local.count(),
+                                self.tracks.len()
+                            );
+                            let playlist = format!("--playlist={}", self.playlist_path.display());
+                            break (vec![playlist], order, label);
+                        }
+                        break (vec![cassette.yt_url.clone()], order, label);
+                    }
+                    None => {
+                        // The cassette disappeared from upstream since it was queued
+                        info!("skipping unknown cassette {}", uuid);
+                        let id = item.id;
+                        self.queue.remove(id);
+                    }
+                },
+                Source::Song(song) => {
+                    info!("playing {}", &song.title);
+                    let url = format!("https://www.youtube.com/watch?v={}", song.id);
+                    let mut track = [Track {
+                        number: 1,
+                        song: song.clone(),
+                        location: Location::Remote { url },
+                        gain: None,
+                    }];
+                    let filters = self.filters(&mut track);
+                    break (track_args(&track, &filters), item.order, String::new());
+                }
+            }
+        };
+
+        if order == Order::Shuffled {
+            command.arg("--shuffle");
+        }
+        let result = command.args(targets).spawn();
+        if let Ok(process) = &result {
+            if let Some(item) = self.queue.current().cloned() {
+                let pid = process.id();
+                self.publish(Event::Started { item, pid });
+            }
+        } else {
+            self.publish(Event::Idle);
+        }
+        self.process = Some(result?);
+        self.playing_since = Some((Instant::now(), label));
+        self.publish_status();
+        Ok(())
+    }
+
+    /// Looks up the gain of the streamed tracks and returns the audio filter each one needs to play
+    /// at the loudness of the profile, if any
+    fn filters(&self, tracks: &mut [Track]) -> Vec<Option<String>> {
+        let mode = self.config.loudness;
+        let filter = |track: &mut Track| {
+            if mode == Loudness::Off || track.is_local() {
+                return None;
+            }
+            track.gain = self.loudness.gain(&track.song);
+            let filter = match (track.gain, mode) {
+                (Some(gain), _) => format!("volume={:.2}dB", gain),
+                (None, Loudness::Loudnorm) => self.loudness.profile().filter(),
+                (None, Loudness::Dynaudnorm) => "dynaudnorm".into(),
+                (None, _) => return None,
+            };
+            Some(format!("lavfi=[{}]", filter))
+        };
+        tracks.iter_mut().map(filter).collect()
+    }
+
+    /// Notifies subscribers about a transition and updates the status
+    fn publish(&self, event: Event) {
+        // Nobody might be listening, which is fine
+        let _ = self.events.send(event);
+        self.publish_status();
+    }
+
+    /// Adds the time the last player process spent playing to the playback metrics
+    fn count_playback(&mut self) {
+        if let Some((since, label)) = self.playing_since.take() {
+            self.metrics
+                .playback
+                .with_label_values(&[&label])
+                .inc_by(since.elapsed().as_secs());
+        }
+    }
+
+    fn publish_status(&self) {
+        let state = match (&self.process, self.restart_at) {
+            (Some(_), _) => PlayerState::Playing,
+            (None, Some(_)) => PlayerState::Restarting,
+            (None, None) => PlayerState::Idle,
+        };
+        self.metrics.set_player_state(state);
+
+        let playing = match (&self.process, self.restart_at) {
+            (None, None) => None,
+            _ => self.queue.current().cloned(),
+        };
+        let tracks = match playing {
+            Some(_) => self.tracks.clone(),
+            None => vec![],
+        };
+        self.status.send_replace(Status {
+            playing,
+            pid: self.process.as_ref().and_then(Child::id),
+            restarts: self.restarts,
+            last_exit: self.last_exit.clone(),
+            tracks,
+        });
+    }
+}
+
+/// The player arguments for a list of tracks, each in a group of its own with its audio filter if
+/// it has one
+fn track_args(tracks: &[Track], filters: &[Option<String>]) -> Vec<String> {
+    let mut args = vec![];
+    for (track, filter) in tracks.iter().zip(filters) {
+        let location = match &track.location {
+            Location::Local { path } => path.display().to_string(),
+            Location::Remote { url } => url.clone(),
+        };
+        match filter {
+            Some(filter) => args.extend([
+                "--{".into(),
+                format!("--af-append={}", filter),
+                location,
+                "--}".into(),
+            ]),
+            None => args.push(location),
+        }
+    }
+    args
+}
+
+/// Waits until it is time to restart the player, or forever if no restart is pending
+async fn restart(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Waits for the player process to exit, or forever if there is no player process
+async fn wait(process: &mut Option<Child>) -> io::Result<ExitStatus> {
+    match process {
+        Some(process) => process.wait().await,
+        None => futures::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exit_reasons() {
+        let exit = |raw| Exit::from(Ok(ExitStatus::from_raw(raw)));
+
+        assert_eq!(exit(0), Exit::Finished);
+        assert_eq!(exit(3 << 8), Exit::Finished);
+        assert_eq!(exit(2 << 8), Exit::Unplayable);
+        let crash = Exit::Crashed {
+            code: Some(1),
+            signal: None,
+        };
+        assert_eq!(exit(1 << 8), crash);
+        let killed = Exit::Crashed {
+            code: None,
+            signal: Some(9),
+        };
+        assert_eq!(exit(9), killed);
+    }
+
+    #[test]
+    fn filtered_tracks() {
+        let track = |number, location| Track {
+            number,
+            song: kasetophono::Song {
+                id: "a".into(),
+                title: "A".into(),
+                duration: None,
+            },
+            location,
+            gain: None,
+        };
+        let tracks = [
+            track(
+                1,
+                Location::Local {
+                    path: "/01 - A.mp3".into(),
+                },
+            ),
+            track(
+                2,
+                Location::Remote {
+                    url: "https://youtu.be/a".into(),
+                },
+            ),
+        ];
+        let filters = [None, Some("lavfi=[volume=-3.50dB]".into())];
+        assert_eq!(
+            track_args(&tracks, &filters),
+            [
+                "/01 - A.mp3",
+                "--{",
+                "--af-append=lavfi=[volume=-3.50dB]",
+                "https://youtu.be/a",
+                "--}"
+            ]
+        );
+    }
+}
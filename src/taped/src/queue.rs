@@ -0,0 +1,300 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use kasetophono::Song;
+
+/// The order in which the songs of a queue item are played
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    /// Play the songs in the order the curator arranged them
+    InOrder,
+    /// Play the songs in random order
+    #[default]
+    Shuffled,
+}
+
+/// What happens when the current queue item finishes playing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Repeat {
+    /// Move on to the next item and stop after the last one
+    #[default]
+    None,
+    /// Play the current item again
+    One,
+    /// Move on to the next item and start over after the last one
+    All,
+}
+
+/// The thing a queue item plays
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// A whole cassette, starting from the track at index `start`
+    Cassette { uuid: Uuid, start: usize },
+    /// A single song
+    Song(Song),
+}
+
+/// An entry of the play queue
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Item {
+    /// A stable identifier of the entry that survives reordering
+    pub id: u64,
+    pub source: Source,
+    pub order: Order,
+}
+
+/// A list of cassettes and songs that are played one after the other
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Queue {
+    items: Vec<Item>,
+    /// The id of the item that is currently playing
+    current: Option<u64>,
+    repeat: Repeat,
+    #[serde(skip)]
+    next_id: u64,
+}
+
+impl Queue {
+    /// Prepares a queue that was read back from disk for use. Nothing is considered to be playing
+    /// since the player process did not survive.
+    pub fn restore(&mut self) {
+        self.next_id = self.items.iter().map(|item| item.id).max().unwrap_or(0);
+        self.current = None;
+    }
+
+    /// Returns the item that is currently playing
+    pub fn current(&self) -> Option<&Item> {
+        self.current.and_then(|id| self.get(id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Item> {
+        self.items.iter().find(|item| item.id == id)
+    }
+
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+    }
+
+    /// Appends a new item at the end of the queue and returns its id
+    pub fn push(&mut self, source: Source, order: Order) -> u64 {
+        let item = self.new_item(source, order);
+        let id = item.id;
+        self.items.push(item);
+        id
+    }
+
+    /// Inserts a new item right after the current one and returns its id
+    pub fn push_next(&mut self, source: Source, order: Order) -> u64 {
+        let position = match self.current_position() {
+            Some(position) => position + 1,
+            None => self.items.len(),
+        };
+        let item = self.new_item(source, order);
+        let id = item.id;
+        self.items.insert(position, item);
+        id
+    }
+
+    /// Removes an item from the queue. If the removed item was the current one the item that
+    /// followed it becomes current.
+    pub fn remove(&mut self, id: u64) -> Option<Item> {
+        let position = self.position(id)?;
+        let item = self.items.remove(position);
+        if self.current == Some(id) {
+            self.current = self.items.get(position).map(|item| item.id);
+        }
+        Some(item)
+    }
+
+    /// Removes all the items from the queue
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.current = None;
+    }
+
+    /// Moves an item to a new position in the queue. Positions past the end of the queue move the
+    /// item to the end.
+    pub fn move_to(&mut self, id: u64, position: usize) -> bool {
+        match self.position(id) {
+            Some(old) => {
+                let item = self.items.remove(old);
+                let position = position.min(self.items.len());
+                self.items.insert(position, item);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Makes the item with the given id the current one
+    pub fn jump(&mut self, id: u64) -> bool {
+        let exists = self.position(id).is_some();
+        if exists {
+            self.current = Some(id);
+        }
+        exists
+    }
+
+    /// Forgets the current item without removing it from the queue
+    pub fn stop(&mut self) {
+        self.current = None;
+    }
+
+    /// Moves to the item that should play after the current one finished, taking the repeat mode
+    /// into account
+    pub fn advance(&mut self) -> Option<&Item> {
+        self.rewind();
+        if self.repeat != Repeat::One {
+            self.skip();
+        }
+        self.current()
+    }
+
+    /// Moves to the next item regardless of whether the current one should be repeated
+    pub fn skip(&mut self) -> Option<&Item> {
+        self.rewind();
+        let next = match self.current_position() {
+            Some(position) => position + 1,
+            None => return None,
+        };
+        self.current = match self.items.get(next) {
+            Some(item) => Some(item.id),
+            None if self.repeat != Repeat::None => self.items.first().map(|item| item.id),
+            None => None,
+        };
+        self.current()
+    }
+
+    /// Makes the current item start from the first track if it ever plays again, as the start
+    /// track only applies to the first time
+    fn rewind(&mut self) {
+        let current = self.current;
+        let item = self.items.iter_mut().find(|item| Some(item.id) == current);
+        if let Some(Item {
+            source: Source::Cassette { start, .. },
+            ..
+        }) = item
+        {
+            *start = 0;
+        }
+    }
+
+    fn new_item(&mut self, source: Source, order: Order) -> Item {
+        self.next_id += 1;
+        Item {
+            id: self.next_id,
+            source,
+            order,
+        }
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        self.items.iter().position(|item| item.id == id)
+    }
+
+    fn current_position(&self) -> Option<usize> {
+        self.current.and_then(|id| self.position(id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn queue(n: u128) -> (Queue, Vec<u64>) {
+        let mut queue = Queue::default();
+        let ids = (0..n)
+            .map(|i| {
+                let uuid = Uuid::from_u128(i);
+                queue.push(Source::Cassette { uuid, start: 0 }, Order::InOrder)
+            })
+            .collect();
+        (queue, ids)
+    }
+
+    fn current(queue: &Queue) -> Option<u64> {
+        queue.current().map(|item| item.id)
+    }
+
+    #[test]
+    fn advance_repeat_modes() {
+        let (mut queue, ids) = queue(2);
+        queue.jump(ids[0]);
+
+        queue.advance();
+        assert_eq!(current(&queue), Some(ids[1]));
+        queue.advance();
+        assert_eq!(current(&queue), None);
+
+        queue.jump(ids[1]);
+        queue.set_repeat(Repeat::All);
+        queue.advance();
+        assert_eq!(current(&queue), Some(ids[0]));
+
+        queue.set_repeat(Repeat::One);
+        queue.advance();
+        assert_eq!(current(&queue), Some(ids[0]));
+        queue.skip();
+        assert_eq!(current(&queue), Some(ids[1]));
+    }
+
+    #[test]
+    fn repeat_from_first_track() {
+        let mut queue = Queue::default();
+        let source = Source::Cassette {
+            uuid: Uuid::nil(),
+            start: 3,
+        };
+        let id = queue.push(source, Order::InOrder);
+        queue.jump(id);
+        queue.set_repeat(Repeat::All);
+
+        queue.advance();
+        assert_eq!(current(&queue), Some(id));
+        assert!(matches!(
+            queue.current().unwrap().source,
+            Source::Cassette { start: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn push_next_and_move() {
+        let (mut queue, ids) = queue(3);
+        queue.jump(ids[0]);
+
+        let next = queue.push_next(
+            Source::Cassette {
+                uuid: Uuid::nil(),
+                start: 0,
+            },
+            Order::Shuffled,
+        );
+        queue.advance();
+        assert_eq!(current(&queue), Some(next));
+
+        assert!(queue.move_to(ids[2], 0));
+        assert!(queue.move_to(ids[0], 100));
+        let order: Vec<_> = queue.items.iter().map(|item| item.id).collect();
+        assert_eq!(order, vec![ids[2], next, ids[1], ids[0]]);
+        assert!(!queue.move_to(1000, 0));
+    }
+
+    #[test]
+    fn remove_current() {
+        let (mut queue, ids) = queue(2);
+        queue.jump(ids[0]);
+
+        assert!(queue.remove(ids[0]).is_some());
+        assert_eq!(current(&queue), Some(ids[1]));
+        assert!(queue.remove(ids[1]).is_some());
+        assert_eq!(current(&queue), None);
+        assert!(queue.remove(ids[1]).is_none());
+    }
+}
@@ -0,0 +1,611 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use log::{debug, info, warn};
+use reqwest::StatusCode;
+use serde::Serialize;
+use tokio::sync::{watch, Notify};
+
+use kasetophono::upstream::Upstream;
+use kasetophono::{scrape::blogger, Cassette, Category, Subcategory};
+
+use crate::catalog::{Cassettes, Catalog, Categories};
+use crate::metrics::Metrics;
+use crate::{config, Result};
+
+/// How many feed pages in a row may fail before the rest of the feed is given up on. The end of
+/// the feed is only known once an empty page is fetched, so it can't be found past failed pages.
+const MAX_FAILED_PAGES: usize = 3;
+
+/// Fetches pages from upstream, retrying failed requests
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    upstream: Upstream,
+    refresh: config::Refresh,
+    metrics: Arc<Metrics>,
+}
+
+/// Controls the refresh task
+#[derive(Clone)]
+pub struct Handle {
+    trigger: Arc<Notify>,
+    status: watch::Receiver<Status>,
+}
+
+/// The state of the catalog refreshes
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Status {
+    /// When the refresh in progress started, if there is one
+    pub running_since: Option<DateTime<Utc>>,
+    /// When the last successful refresh finished
+    pub last_success: Option<DateTime<Utc>>,
+    /// When the last failed refresh finished
+    pub last_failure: Option<DateTime<Utc>>,
+    /// Why the last failed refresh failed
+    pub last_error: Option<String>,
+    /// When the next refresh is scheduled to start
+    pub next_refresh: Option<DateTime<Utc>>,
+    /// What happened during the last finished refresh, successful or not
+    pub last: Option<Stats>,
+}
+
+/// What happened during a refresh. The counts are those reached before the refresh finished or
+/// failed.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Stats {
+    pub categories: usize,
+    pub subcategories: usize,
+    /// How many feed pages were fetched, including the empty one that marks the end of the feed
+    pub feed_pages: usize,
+    /// How many category and feed pages could not be fetched
+    pub failed_pages: usize,
+    pub cassettes: usize,
+    /// Whether the refresh finished without some pages, in which case the cassettes it fetched
+    /// were merged with the previous catalog
+    pub partial: bool,
+    pub durations: Durations,
+}
+
+/// How long each phase of a refresh took, in seconds
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Durations {
+    /// Fetching the frontpage and scraping the categories from it
+    pub frontpage: f64,
+    /// Fetching the category pages and scraping the subcategories from them
+    pub categories: f64,
+    /// Fetching the feed pages
+    pub feed: f64,
+    pub total: f64,
+}
+
+impl Handle {
+    pub fn status(&self) -> Status {
+        self.status.borrow().clone()
+    }
+
+    /// Starts a refresh right away, unless one is already running. Returns whether a refresh was
+    /// started.
+    pub fn trigger(&self) -> bool {
+        if self.status.borrow().running_since.is_some() {
+            return false;
+        }
+        self.trigger.notify_one();
+        true
+    }
+}
+
+impl Client {
+    pub fn new(
+        upstream: Upstream,
+        refresh: config::Refresh,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(refresh.timeout)
+            .user_agent(&refresh.user_agent)
+            .build()?;
+        Ok(Self {
+            http,
+            upstream,
+            refresh,
+            metrics,
+        })
+    }
+
+    /// Fetches the body of `url`, retrying with exponential backoff on network errors and on
+    /// responses that may succeed when tried again. `phase` labels the requests in the metrics.
+    async fn get(&self, phase: &str, url: &str) -> Result<String> {
+        let mut attempt = 1;
+        loop {
+            let start = Instant::now();
+            let result = async {
+                let response = self.http.get(url).send().await?.error_for_status()?;
+                response.text().await
+            }
+            .await;
+            self.metrics
+                .upstream_request(phase, result.is_ok(), start.elapsed());
+
+            match result {
+                Ok(body) => return Ok(body),
+                Err(err) if attempt < self.refresh.attempts && is_transient(&err) => {
+                    let delay = self.backoff(attempt);
+                    debug!("fetching {} failed, retrying in {:?}: {}", url, delay, err);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// How long to wait before retrying a request that failed `attempt` times. The delay doubles
+    /// with every attempt and is randomly shortened by up to a half, so that requests that failed
+    /// together don't all retry at the same time.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .refresh
+            .backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.refresh.max_backoff);
+        delay.mul_f64(0.5 + fastrand::f64() / 2.0)
+    }
+
+    /// Records that a page failed to be fetched. Fails the whole refresh unless partial refreshes
+    /// are allowed.
+    fn failed(&self, url: &str, err: anyhow::Error) -> Result<()> {
+        if !self.refresh.allow_partial {
+            return Err(err.context(format!("fetching {}", url)));
+        }
+        warn!("giving up on {}: {}", url, err);
+        Ok(())
+    }
+}
+
+/// Whether a failed request may succeed if tried again
+fn is_transient(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        None => !err.is_builder() && !err.is_redirect(),
+    }
+}
+
+async fn subcategories(
+    categories: &[Category],
+    client: &Client,
+    stats: &mut Stats,
+) -> Result<Vec<(String, Vec<Subcategory>)>> {
+    let mut responses = stream::iter(categories)
+        .map(|c| async move {
+            let url = client.upstream.rewrite(&c.url);
+            let result = client.get("category", &url).await;
+            (c, url, result)
+        })
+        .buffer_unordered(client.refresh.concurrency)
+        // Workaround for rust-lang/rust#89976
+        .boxed();
+
+    let mut subcategories = vec![];
+    while let Some((category, url, result)) = responses.next().await {
+        let subs = result
+            .and_then(|response| kasetophono::scrape::subcategory::scrape_subcategories(&response));
+        match subs {
+            Ok(subs) => subcategories.push((category.name.clone(), subs)),
+            Err(err) => {
+                stats.failed_pages += 1;
+                client.failed(&url, err)?;
+            }
+        }
+    }
+    stats.subcategories = subcategories.iter().map(|(_, subs)| subs.len()).sum();
+    Ok(subcategories)
+}
+
+/// Returns a feed page that can be deserialized, working around known upstream bugs
+fn repair_feed(response: String) -> Result<String> {
+    if serde_json::from_str::<blogger::Document>(&response).is_ok() {
+        return Ok(response);
+    }
+    // The website suddenly started serving responses with invalid JSON where two objects are
+    // separated by two commas instead of one. Probably a bug somewhere in Google. Workaround by
+    // retrying the deserialization after replacing all double commas with single ones
+    let response = response.replace(",,", ",");
+    serde_json::from_str::<blogger::Document>(&response)?;
+    Ok(response)
+}
+
+async fn cassettes(
+    subcategories: &[Subcategory],
+    client: &Client,
+    stats: &mut Stats,
+) -> Result<Cassettes> {
+    let page_size = client.refresh.page_size;
+    let mut responses = stream::iter((1..).step_by(page_size))
+        .map(|page| async move {
+            let url = client.upstream.feed(page, page_size);
+            let result = client.get("feed", &url).await;
+            (url, result)
+        })
+        // The pages must be processed in order since the first empty one marks the end of the feed
+        .buffered(client.refresh.concurrency);
+
+    let mut cassettes = Cassettes::new();
+    let mut failed_pages = 0;
+    while let Some((url, result)) = responses.next().await {
+        let response = match result.and_then(repair_feed) {
+            Ok(response) => response,
+            Err(err) => {
+                stats.failed_pages += 1;
+                client.failed(&url, err)?;
+                failed_pages += 1;
+                if failed_pages == MAX_FAILED_PAGES {
+                    warn!("giving up on the rest of the feed");
+                    break;
+                }
+                continue;
+            }
+        };
+        failed_pages = 0;
+        stats.feed_pages += 1;
+
+        let document: blogger::Document = serde_json::from_str(&response)?;
+
+        let mut empty = true;
+        for entry in document.feed.entry {
+            if let Some(mut cassette) = Cassette::try_from_entry(entry) {
+                empty = false;
+                cassette.fill_subcategories(subcategories);
+                cassettes.insert(cassette.uuid, cassette);
+            }
+        }
+        stats.cassettes = cassettes.len();
+        if empty {
+            break;
+        }
+    }
+
+    let total = cassettes.len();
+    debug!("fetched {} cassettes", total);
+
+    // let mut i = 0;
+    // cassettes.retain(move |_uuid, cassette| {
+    //     i += 1;
+    //     debug!("fetching songs ({}/{}): {:?}", i, total, &cassette.name);
+    //     match cassette.fill_songs() {
+    //         Ok(()) => true,
+    //         Err(e) => {
+    //             debug!("discarding cassette: {:?}: {}", &cassette.name, e);
+    //             false
+    //         }
+    //     }
+    // });
+
+    Ok(cassettes)
+}
+
+async fn load_cassettes(client: &Client, stats: &mut Stats) -> Result<(Cassettes, Categories)> {
+    let start = Instant::now();
+    let body = client
+        .get("frontpage", &client.upstream.frontpage())
+        .await?;
+    let categories = kasetophono::scrape::category::scrape_categories(&body)?;
+    stats.categories = categories.len();
+    stats.durations.frontpage = start.elapsed().as_secs_f64();
+
+    let start = Instant::now();
+    let by_category = subcategories(&categories, client, stats).await?;
+    stats.durations.categories = start.elapsed().as_secs_f64();
+    let mut categories = Categories::new();
+    let mut subcategories = vec![];
+    for (category, subs) in by_category {
+        let names = subs.iter().map(|sub| sub.name.clone()).collect();
+        categories.insert(category, names);
+        subcategories.extend(subs);
+    }
+
+    let start = Instant::now();
+    let cassettes = cassettes(&subcategories, client, stats).await?;
+    stats.durations.feed = start.elapsed().as_secs_f64();
+
+    stats.partial = stats.failed_pages > 0;
+    if cassettes.is_empty() && stats.partial {
+        anyhow::bail!("no feed page could be fetched");
+    }
+    Ok((cassettes, categories))
+}
+
+/// Starts the task that periodically refreshes the catalog from upstream
+pub fn spawn(catalog: Arc<ArcSwap<Catalog>>, snapshot_path: PathBuf, client: Client) -> Handle {
+    let (status_tx, status) = watch::channel(Status {
+        last_success: catalog.load().fetched_at,
+        ..Default::default()
+    });
+    let trigger = Arc::new(Notify::new());
+    tokio::spawn(refresh_loop(
+        catalog,
+        snapshot_path,
+        client,
+        trigger.clone(),
+        status_tx,
+    ));
+    Handle { trigger, status }
+}
+
+/// Waits for `duration` to pass or for a refresh to be requested, whichever comes first
+async fn wait(duration: Duration, trigger: &Notify, status: &watch::Sender<Status>) {
+    let next = chrono::Duration::from_std(duration).ok();
+    status.send_modify(|status| status.next_refresh = next.map(|next| Utc::now() + next));
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        _ = trigger.notified() => info!("refresh requested"),
+    }
+}
+
+async fn refresh_loop(
+    catalog: Arc<ArcSwap<Catalog>>,
+    snapshot_path: PathBuf,
+    client: Client,
+    trigger: Arc<Notify>,
+    status: watch::Sender<Status>,
+) {
+    let refresh = &client.refresh;
+
+    // A catalog loaded from a recent enough snapshot doesn't need to be fetched again right away,
+    // unless the refresh that produced it was partial
+    let current = catalog.load();
+    if let (Some(fetched_at), false) = (current.fetched_at, current.partial) {
+        let age = (Utc::now() - fetched_at).to_std().unwrap_or_default();
+        if let Some(remaining) = refresh.interval.checked_sub(age) {
+            info!(
+                "catalog snapshot is recent, next refresh in {:?}",
+                remaining
+            );
+            wait(remaining, &trigger, &status).await;
+        }
+    }
+    drop(current);
+
+    loop {
+        info!("loading cassettes from upstream");
+        status.send_modify(|status| {
+            status.running_since = Some(Utc::now());
+            status.next_refresh = None;
+        });
+
+        let start = Instant::now();
+        let mut stats = Stats::default();
+        let result = load_cassettes(&client, &mut stats).await;
+        stats.durations.total = start.elapsed().as_secs_f64();
+        let metrics = &client.metrics;
+        metrics.refresh_duration.observe(stats.durations.total);
+        let outcome = match &result {
+            Ok(_) if stats.partial => "partial",
+            Ok(_) => "success",
+            Err(_) => "failure",
+        };
+        metrics.refreshes.with_label_values(&[outcome]).inc();
+
+        let delay = match result {
+            Ok((cassettes, categories)) => {
+                let previous = catalog.load_full();
+                let mut new = if stats.partial {
+                    warn!(
+                        "refresh was partial, merging {} cassettes into the previous catalog",
+                        cassettes.len()
+                    );
+                    previous.merge(cassettes, categories)
+                } else {
+                    Catalog::new(cassettes, categories)
+                };
+                new.generation = previous.generation + 1;
+                let new = Arc::new(new);
+                catalog.store(new.clone());
+
+                let path = snapshot_path.clone();
+                let saved = tokio::task::spawn_blocking(move || new.save(&path)).await;
+                if let Err(err) = saved.map_err(Into::into).and_then(|saved| saved) {
+                    info!("failed to save catalog snapshot: {}", err);
+                }
+
+                status.send_modify(|status| status.last_success = Some(Utc::now()));
+                refresh.interval
+            }
+            Err(err) => {
+                info!("failed to get cassettes from upstream: {:#}", err);
+                status.send_modify(|status| {
+                    status.last_failure = Some(Utc::now());
+                    status.last_error = Some(format!("{:#}", err));
+                });
+                refresh.retry
+            }
+        };
+        status.send_modify(|status| {
+            status.running_since = None;
+            status.last = Some(stats);
+        });
+
+        wait(delay, &trigger, &status).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use axum::extract::Query;
+    use axum::response::{Html, IntoResponse};
+    use axum::routing::get;
+    use axum::Router;
+
+    use super::*;
+    use crate::catalog;
+
+    const FRONTPAGE: &str = include_str!("../../kasetophono/assets/frontpage.html");
+    const CATEGORY: &str = include_str!("../../kasetophono/assets/category.html");
+    const FEED: &str = include_str!("../../kasetophono/assets/feed.json");
+
+    /// Serves the scraper fixtures of the kasetophono crate like kasetophono.com would. The feed
+    /// fixture is the first page and every following page is empty. A flaky server fails the
+    /// first request for the first feed page and every request for a category page.
+    async fn fixture_server(flaky: bool) -> SocketAddr {
+        let failed_feed = Arc::new(AtomicBool::new(!flaky));
+        let feed = move |Query(query): Query<HashMap<String, String>>| async move {
+            if query.get("start-index").map(String::as_str) != Some("1") {
+                let mut document: blogger::Document = serde_json::from_str(FEED).unwrap();
+                document.feed.entry.clear();
+                Ok(serde_json::to_string(&document).unwrap())
+            } else if failed_feed.swap(true, Ordering::SeqCst) {
+                Ok(FEED.to_string())
+            } else {
+                Err(StatusCode::SERVICE_UNAVAILABLE)
+            }
+        };
+        let category = move || async move {
+            match flaky {
+                true => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                false => Html(CATEGORY).into_response(),
+            }
+        };
+        let app = Router::new()
+            .route("/", get(|| async { Html(FRONTPAGE) }))
+            .route("/p/:page", get(category))
+            .route("/feeds/posts/default", get(feed));
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    fn client(addr: SocketAddr, allow_partial: bool) -> Client {
+        let refresh = config::Refresh {
+            backoff: Duration::from_millis(1),
+            allow_partial,
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::new().unwrap());
+        Client::new(Upstream::new(&format!("http://{}", addr)), refresh, metrics).unwrap()
+    }
+
+    fn feed_cassettes() -> usize {
+        let document: blogger::Document = serde_json::from_str(FEED).unwrap();
+        document
+            .feed
+            .entry
+            .into_iter()
+            .filter_map(Cassette::try_from_entry)
+            .count()
+    }
+
+    #[tokio::test]
+    async fn load_from_mirror() {
+        let addr = fixture_server(false).await;
+
+        let mut stats = Stats::default();
+        let (cassettes, categories) = load_cassettes(&client(addr, false), &mut stats)
+            .await
+            .unwrap();
+
+        assert!(feed_cassettes() > 0);
+        assert_eq!(cassettes.len(), feed_cassettes());
+        assert_eq!(stats.cassettes, cassettes.len());
+        assert_eq!(stats.feed_pages, 2);
+        assert_eq!(stats.failed_pages, 0);
+        assert!(stats.categories > 0);
+        assert_eq!(categories.len(), stats.categories);
+        assert!(categories.values().all(|names| !names.is_empty()));
+        assert!(!stats.partial);
+        assert!(cassettes.values().any(|c| !c.subcategories.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn partial_refresh() {
+        let addr = fixture_server(true).await;
+
+        // The feed page is retried, but the category pages never succeed
+        let mut stats = Stats::default();
+        let (cassettes, categories) = load_cassettes(&client(addr, true), &mut stats)
+            .await
+            .unwrap();
+        assert!(categories.is_empty());
+        assert_eq!(cassettes.len(), feed_cassettes());
+        assert_eq!(stats.failed_pages, stats.categories);
+        assert!(stats.partial);
+        assert!(cassettes.values().all(|c| c.subcategories.is_empty()));
+
+        let mut stats = Stats::default();
+        assert!(load_cassettes(&client(addr, false), &mut stats)
+            .await
+            .is_err());
+        assert_eq!(stats.failed_pages, 1);
+    }
+
+    #[tokio::test]
+    async fn partial_refresh_keeps_subcategories() {
+        let addr = fixture_server(false).await;
+        let mut stats = Stats::default();
+        let (cassettes, categories) = load_cassettes(&client(addr, false), &mut stats)
+            .await
+            .unwrap();
+        let previous = Catalog::new(cassettes, categories);
+
+        // None of the category pages can be fetched this time
+        let addr = fixture_server(true).await;
+        let mut stats = Stats::default();
+        let (cassettes, categories) = load_cassettes(&client(addr, true), &mut stats)
+            .await
+            .unwrap();
+        assert!(cassettes.values().all(|c| c.subcategories.is_empty()));
+        let merged = previous.merge(cassettes, categories);
+
+        assert_eq!(merged.categories, previous.categories);
+        for (uuid, cassette) in merged.cassettes.iter() {
+            assert_eq!(
+                cassette.subcategories,
+                previous.cassettes[uuid].subcategories
+            );
+        }
+        // The cassettes can still be listed by category
+        let query = |category: &str| catalog::Query {
+            category: Some(category.to_string()),
+            ..Default::default()
+        };
+        let listed = |catalog: &Catalog, category| catalog.query(&query(category)).total;
+        let category = previous
+            .categories
+            .keys()
+            .find(|category| listed(&previous, category) > 0)
+            .unwrap();
+        assert_eq!(listed(&merged, category), listed(&previous, category));
+    }
+
+    #[test]
+    fn merge() {
+        let document: blogger::Document = serde_json::from_str(FEED).unwrap();
+        let mut cassettes: Cassettes = document
+            .feed
+            .entry
+            .into_iter()
+            .filter_map(Cassette::try_from_entry)
+            .map(|cassette| (cassette.uuid, cassette))
+            .collect();
+        let previous = Catalog::new(cassettes.clone(), Categories::new());
+
+        let uuid = *cassettes.keys().next().unwrap();
+        cassettes.retain(|&u, _| u == uuid);
+        cassettes.get_mut(&uuid).unwrap().name = "renamed".into();
+
+        let merged = previous.merge(cassettes, Categories::new());
+        assert!(merged.partial);
+        assert_eq!(merged.cassettes.len(), previous.cassettes.len());
+        assert_eq!(merged.cassettes[&uuid].name, "renamed");
+    }
+}
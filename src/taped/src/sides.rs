@@ -0,0 +1,263 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use kasetophono::Cassette;
+
+/// The silence at the start of each side, which covers the leader of a real tape
+pub const LEADER: Duration = Duration::from_secs(5);
+
+/// The dimensions of a J-card in millimetres: the front panel, the spine and the back flap
+const FRONT: f32 = 63.5;
+const SPINE: f32 = 12.7;
+const FLAP: f32 = 15.0;
+const WIDTH: f32 = 101.6;
+const MARGIN: f32 = 3.0;
+
+/// The most characters of a title that fit in a column of the J-card
+const TITLE_CHARS: usize = 34;
+
+/// The lengths of compact cassettes, named after their playing time in minutes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TapeLength {
+    C46,
+    C60,
+    C90,
+}
+
+impl TapeLength {
+    /// How much fits on one side
+    pub fn side(self) -> Duration {
+        let minutes = match self {
+            TapeLength::C46 => 23,
+            TapeLength::C60 => 30,
+            TapeLength::C90 => 45,
+        };
+        Duration::from_secs(minutes * 60)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TapeLength::C46 => "C46",
+            TapeLength::C60 => "C60",
+            TapeLength::C90 => "C90",
+        }
+    }
+}
+
+/// Which tracks go on which side of a tape, as indices into the tracks of the cassette
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Sides {
+    pub a: Vec<usize>,
+    pub b: Vec<usize>,
+    /// The tracks that didn't fit on either side
+    pub left_out: Vec<usize>,
+}
+
+/// Packs tracks of the given durations onto the two sides of a tape, each side starting with the
+/// leader. The curator's order is kept if the tracks can be split in two anywhere, otherwise
+/// tracks that don't fit where they are due are moved to side B, or back to side A, and only left
+/// out as a last resort.
+pub fn pack(durations: &[Duration], length: TapeLength) -> Sides {
+    let capacity = length.side().saturating_sub(LEADER);
+    let fits = |tracks: &[Duration]| tracks.iter().sum::<Duration>() <= capacity;
+
+    // Side A is filled as far as possible, like when recording a tape in one go
+    let split = (0..=durations.len())
+        .rev()
+        .find(|&split| fits(&durations[..split]) && fits(&durations[split..]));
+    if let Some(split) = split {
+        return Sides {
+            a: (0..split).collect(),
+            b: (split..durations.len()).collect(),
+            left_out: vec![],
+        };
+    }
+
+    let mut sides = Sides::default();
+    let (mut a, mut b) = (Duration::ZERO, Duration::ZERO);
+    for (index, &duration) in durations.iter().enumerate() {
+        if sides.b.is_empty() && a + duration <= capacity {
+            a += duration;
+            sides.a.push(index);
+        } else if b + duration <= capacity {
+            b += duration;
+            sides.b.push(index);
+        } else if a + duration <= capacity {
+            a += duration;
+            sides.a.push(index);
+        } else {
+            sides.left_out.push(index);
+        }
+    }
+    sides
+}
+
+/// Renders a printable J-card for a tape as an SVG at its real size. The front panel lists the
+/// tracks of both sides, the spine carries the name of the cassette and the flap its date. Tracks
+/// are given by their number in the cassette and their title.
+pub fn jcard(
+    cassette: &Cassette,
+    titles: &[(usize, &str)],
+    durations: &[Duration],
+    sides: &Sides,
+    length: TapeLength,
+) -> String {
+    let height = FRONT + SPINE + FLAP;
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}mm" height="{h}mm" viewBox="0 0 {w} {h}" font-family="sans-serif">"#,
+        w = WIDTH,
+        h = height
+    );
+    let _ = writeln!(
+        svg,
+        r#"<rect x="0" y="0" width="{}" height="{}" fill="white" stroke="black" stroke-width="0.2"/>"#,
+        WIDTH, height
+    );
+    // Fold lines between the panels
+    for y in [FRONT, FRONT + SPINE] {
+        let _ = writeln!(
+            svg,
+            r#"<line x1="0" y1="{y}" x2="{}" y2="{y}" stroke="gray" stroke-width="0.1" stroke-dasharray="1,1"/>"#,
+            WIDTH,
+            y = y
+        );
+    }
+
+    text(&mut svg, MARGIN, MARGIN + 4.0, 4.5, "bold", &cassette.name);
+    let rows = sides.a.len().max(sides.b.len()).max(1);
+    let top = MARGIN + 11.0;
+    // Long sides get a smaller font so that every track fits on the front panel
+    let line = ((FRONT - top - MARGIN) / (rows + 1) as f32).min(3.5);
+    let column = (WIDTH - 2.0 * MARGIN) / 2.0;
+    for (side, tracks, x) in [("A", &sides.a, MARGIN), ("B", &sides.b, MARGIN + column)] {
+        let total: Duration = tracks.iter().map(|&index| durations[index]).sum();
+        let heading = format!("Side {} · {}", side, minutes(total));
+        text(&mut svg, x, top - 2.0, 3.0, "bold", &heading);
+        for (row, &index) in tracks.iter().enumerate() {
+            let y = top + line * (row + 1) as f32;
+            let (number, title) = titles[index];
+            let title = format!("{}. {}", number, ellipsize(title));
+            text(&mut svg, x, y, line * 0.8, "normal", &title);
+        }
+    }
+
+    let spine = FRONT + SPINE / 2.0 + 1.5;
+    text(&mut svg, MARGIN, spine, 4.0, "bold", &cassette.name);
+    let _ = writeln!(
+        svg,
+        r#"<text x="{}" y="{}" font-size="3" text-anchor="end">{}</text>"#,
+        WIDTH - MARGIN,
+        spine,
+        length.name()
+    );
+    let date = cassette.created_at.get(..10).unwrap_or_default();
+    let flap = FRONT + SPINE + FLAP / 2.0 + 1.0;
+    text(
+        &mut svg,
+        MARGIN,
+        flap,
+        3.0,
+        "normal",
+        &format!("Kasetophono · {}", date),
+    );
+    if !sides.left_out.is_empty() {
+        let left_out = format!("{} tracks didn't fit", sides.left_out.len());
+        text(&mut svg, MARGIN, flap + 4.0, 2.5, "normal", &left_out);
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn text(svg: &mut String, x: f32, y: f32, size: f32, weight: &str, content: &str) {
+    let _ = writeln!(
+        svg,
+        r#"<text x="{:.2}" y="{:.2}" font-size="{:.2}" font-weight="{}">{}</text>"#,
+        x,
+        y,
+        size,
+        weight,
+        escape(content)
+    );
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn ellipsize(title: &str) -> String {
+    match title.char_indices().nth(TITLE_CHARS) {
+        Some((end, _)) => format!("{}…", &title[..end]),
+        None => title.to_string(),
+    }
+}
+
+/// Formats a duration as `m:ss`
+fn minutes(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn durations(minutes: &[u64]) -> Vec<Duration> {
+        minutes
+            .iter()
+            .map(|&m| Duration::from_secs(m * 60))
+            .collect()
+    }
+
+    #[test]
+    fn packing() {
+        // In order, with side A as full as it gets
+        let sides = pack(&durations(&[10, 10, 4, 10, 10]), TapeLength::C60);
+        assert_eq!(sides.a, [0, 1, 2]);
+        assert_eq!(sides.b, [3, 4]);
+        assert!(sides.left_out.is_empty());
+
+        // The last track goes back to side A rather than being left out like the long one
+        let sides = pack(&durations(&[20, 15, 25, 8, 9]), TapeLength::C60);
+        assert_eq!(sides.a, [0, 4]);
+        assert_eq!(sides.b, [1, 3]);
+        assert_eq!(sides.left_out, [2]);
+
+        let sides = pack(&durations(&[40, 40]), TapeLength::C90);
+        assert_eq!((sides.a.len(), sides.b.len()), (1, 1));
+    }
+
+    #[test]
+    fn jcards() {
+        let cassette = Cassette {
+            name: "Rock & Roll".into(),
+            safe_name: "Rock & Roll".into(),
+            created_at: "2019-01-20T10:00:00.000+02:00".into(),
+            ..Default::default()
+        };
+        let durations = durations(&[3, 4]);
+        let sides = pack(&durations, TapeLength::C46);
+        let svg = jcard(
+            &cassette,
+            // The second track is missing from the library
+            &[(1, "First"), (3, "Third")],
+            &durations,
+            &sides,
+            TapeLength::C46,
+        );
+
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(">Rock &amp; Roll<"));
+        assert!(svg.contains(">Side A · 7:00<"));
+        assert!(svg.contains(">3. Third<"));
+        assert!(svg.contains(">Kasetophono · 2019-01-20<"));
+        assert_eq!(ellipsize(&"a".repeat(40)), format!("{}…", "a".repeat(34)));
+    }
+}
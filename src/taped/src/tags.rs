@@ -0,0 +1,163 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+
+use kasetophono::{Cassette, Song};
+
+use crate::audio::{self, Codec};
+use crate::{persist, Result};
+
+mod id3;
+mod mp4;
+mod vorbis;
+
+/// The album artist of every cassette
+const ALBUM_ARTIST: &str = "Kasetophono";
+
+/// The MusicBrainz release type of cassettes, which are mixtapes of other artists' songs
+const RELEASE_TYPE: &str = "compilation";
+
+/// The tags of a track of a cassette
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tags {
+    pub title: String,
+    /// The artist, if the title of the song names one
+    pub artist: Option<String>,
+    /// The name and month of the cassette
+    pub album: String,
+    pub album_artist: String,
+    /// The 1-based track number
+    pub track: usize,
+    pub track_total: usize,
+    /// The labels of the cassette
+    pub genres: Vec<String>,
+    /// The day the cassette was posted, as `YYYY-MM-DD`
+    pub date: String,
+    pub curator: Option<String>,
+    /// The video the track was downloaded from
+    pub source: String,
+}
+
+/// A front cover
+pub struct Picture {
+    pub mime: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Tags {
+    pub fn new(cassette: &Cassette, track: usize, song: &Song) -> Result<Self> {
+        let (album, date) = audio::album(cassette)?;
+        let (artist, title) = split_title(&song.title);
+        Ok(Self {
+            title: title.to_string(),
+            artist: artist.map(str::to_string),
+            album,
+            album_artist: ALBUM_ARTIST.to_string(),
+            track,
+            track_total: cassette.videos.len(),
+            genres: cassette.labels.clone(),
+            date: date.to_string(),
+            curator: cassette.curator.clone(),
+            source: format!("https://www.youtube.com/watch?v={}", song.id),
+        })
+    }
+}
+
+impl Picture {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let data = tokio::fs::read(path).await?;
+        let image = image::load_from_memory(&data).context("reading cover")?;
+        let mime = match image::guess_format(&data)? {
+            image::ImageFormat::Png => "image/png",
+            _ => "image/jpeg",
+        };
+        Ok(Self {
+            mime,
+            width: image.width(),
+            height: image.height(),
+            data,
+        })
+    }
+
+    /// The picture as a FLAC picture block, which is also how Ogg files carry it
+    pub fn flac_block(&self) -> Vec<u8> {
+        vorbis::picture_block(self)
+    }
+}
+
+/// Copies the track at `input` to `output` with its tags replaced, in the format implied by the
+/// extension of `output`. Tags that taped doesn't set are kept, and the audio is copied as is.
+pub async fn write(
+    input: &Path,
+    output: &Path,
+    tags: &Tags,
+    picture: Option<&Picture>,
+) -> Result<()> {
+    let codec = Codec::from_path(output)
+        .ok_or_else(|| anyhow!("unknown audio container {}", output.display()))?;
+    let data = tokio::fs::read(input).await?;
+    let tagged = match codec {
+        Codec::Mp3 => id3::tag(&data, tags, picture),
+        Codec::Aac => mp4::tag(&data, tags, picture),
+        Codec::Flac => vorbis::tag_flac(&data, tags, picture),
+        Codec::Opus => vorbis::tag_opus(&data, tags, picture),
+    };
+    let tagged = tagged.with_context(|| format!("tagging {}", input.display()))?;
+    let output = output.to_owned();
+    tokio::task::spawn_blocking(move || persist::write(&output, &tagged)).await?
+}
+
+/// Splits a song title like `Artist - Title` into its artist and title
+fn split_title(title: &str) -> (Option<&str>, &str) {
+    match title.split_once(" - ") {
+        Some((artist, title)) if !artist.trim().is_empty() && !title.trim().is_empty() => {
+            (Some(artist.trim()), title.trim())
+        }
+        _ => (None, title.trim()),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub fn tags() -> Tags {
+        Tags {
+            title: "Thunderstruck".into(),
+            artist: Some("AC/DC".into()),
+            album: "Nero | 2019/01".into(),
+            album_artist: ALBUM_ARTIST.into(),
+            track: 3,
+            track_total: 12,
+            genres: vec!["Rock".into(), "80s".into()],
+            date: "2019-01-20".into(),
+            curator: Some("Eleni".into()),
+            source: "https://www.youtube.com/watch?v=v2AC41dglnM".into(),
+        }
+    }
+
+    pub fn picture() -> Picture {
+        Picture {
+            mime: "image/png",
+            width: 3,
+            height: 2,
+            data: b"\x89PNG...".to_vec(),
+        }
+    }
+
+    #[test]
+    fn titles() {
+        assert_eq!(
+            split_title("AC/DC - Thunderstruck"),
+            (Some("AC/DC"), "Thunderstruck")
+        );
+        assert_eq!(
+            split_title("Σωκράτης Μάλαμας - Πριγκηπέσσα - Live"),
+            (Some("Σωκράτης Μάλαμας"), "Πριγκηπέσσα - Live")
+        );
+        assert_eq!(split_title("Intro"), (None, "Intro"));
+        assert_eq!(split_title(" - Intro"), (None, "- Intro"));
+    }
+}
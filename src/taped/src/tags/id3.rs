@@ -0,0 +1,265 @@
+use anyhow::{anyhow, bail};
+
+use super::{Picture, Tags, RELEASE_TYPE};
+use crate::Result;
+
+/// Frames taped sets, which replace the ones already in the file
+const FRAMES: [&[u8; 4]; 9] = [
+    b"TIT2", b"TPE1", b"TALB", b"TPE2", b"TRCK", b"TCON", b"TDRC", b"TCMP", b"WOAS",
+];
+
+/// ID3v2.3 frames that ID3v2.4 replaced or dropped, and so can't be carried over
+const OBSOLETE: [&[u8; 4]; 9] = [
+    b"TYER", b"TDAT", b"TIME", b"TRDA", b"TSIZ", b"TORY", b"EQUA", b"RVAD", b"IPLS",
+];
+
+/// Descriptions of the user defined text frames taped sets
+const USER_TEXT: [&str; 2] = ["MusicBrainz Album Type", "CURATOR"];
+
+/// Header flags: unsynchronisation, extended header and footer present
+const UNSYNCHRONISED: u8 = 0x80;
+const EXTENDED_HEADER: u8 = 0x40;
+const FOOTER: u8 = 0x10;
+
+/// The size of the tag header and footer
+const HEADER: usize = 10;
+
+/// A frame of an existing tag
+#[derive(Debug)]
+struct Frame<'a> {
+    id: [u8; 4],
+    flags: [u8; 2],
+    body: &'a [u8],
+}
+
+/// Replaces the ID3v2 tag of an MP3 file with an ID3v2.4 one. Frames of the old tag that taped
+/// doesn't set are kept, and so is the cover unless there's a new one.
+pub fn tag(data: &[u8], tags: &Tags, picture: Option<&Picture>) -> Result<Vec<u8>> {
+    let (frames, audio) = split(data)?;
+    // An ID3v1 tag at the end would shadow the new tag in some players
+    let audio = match audio.len().checked_sub(128) {
+        Some(end) if audio[end..].starts_with(b"TAG") => &audio[..end],
+        _ => audio,
+    };
+
+    let mut body = vec![];
+    for frame in frames {
+        let replaced = FRAMES.contains(&&frame.id)
+            || OBSOLETE.contains(&&frame.id)
+            || (&frame.id == b"TXXX"
+                && USER_TEXT.contains(&description(&frame).as_deref().unwrap_or_default()))
+            || (&frame.id == b"APIC" && picture.is_some());
+        if !replaced {
+            push_frame(&mut body, &frame.id, frame.flags, frame.body);
+        }
+    }
+
+    text(&mut body, b"TIT2", &[&tags.title]);
+    if let Some(artist) = &tags.artist {
+        text(&mut body, b"TPE1", &[artist]);
+    }
+    text(&mut body, b"TALB", &[&tags.album]);
+    text(&mut body, b"TPE2", &[&tags.album_artist]);
+    let track = format!("{}/{}", tags.track, tags.track_total);
+    text(&mut body, b"TRCK", &[&track]);
+    if !tags.genres.is_empty() {
+        let genres: Vec<&str> = tags.genres.iter().map(String::as_str).collect();
+        text(&mut body, b"TCON", &genres);
+    }
+    text(&mut body, b"TDRC", &[&tags.date]);
+    text(&mut body, b"TCMP", &["1"]);
+    user_text(&mut body, "MusicBrainz Album Type", RELEASE_TYPE);
+    if let Some(curator) = &tags.curator {
+        user_text(&mut body, "CURATOR", curator);
+    }
+    push_frame(&mut body, b"WOAS", [0; 2], tags.source.as_bytes());
+    if let Some(picture) = picture {
+        // UTF-8, the MIME type, a front cover and an empty description
+        let mut frame = vec![3];
+        frame.extend(picture.mime.as_bytes());
+        frame.extend([0, 3, 0]);
+        frame.extend(&picture.data);
+        push_frame(&mut body, b"APIC", [0; 2], &frame);
+    }
+
+    let mut tagged = Vec::with_capacity(HEADER + body.len() + audio.len());
+    tagged.extend(b"ID3\x04\x00\x00");
+    tagged.extend(syncsafe(body.len())?);
+    tagged.extend(body);
+    tagged.extend(audio);
+    Ok(tagged)
+}
+
+/// Splits an MP3 file into the frames of its ID3v2 tag and its audio. Tags that can't be carried
+/// over as they are, like ID3v2.2 or unsynchronised ones, are left out entirely.
+fn split(data: &[u8]) -> Result<(Vec<Frame<'_>>, &[u8])> {
+    if !data.starts_with(b"ID3") {
+        return Ok((vec![], data));
+    }
+    let header = data
+        .get(..HEADER)
+        .ok_or_else(|| anyhow!("truncated ID3 header"))?;
+    let (version, flags) = (header[3], header[5]);
+    let mut end = HEADER + unsyncsafe(&header[6..10]);
+    if flags & FOOTER != 0 {
+        end += HEADER;
+    }
+    let audio = data
+        .get(end..)
+        .ok_or_else(|| anyhow!("truncated ID3 tag"))?;
+    if !matches!(version, 3 | 4) || flags & (UNSYNCHRONISED | EXTENDED_HEADER) != 0 {
+        return Ok((vec![], audio));
+    }
+
+    let mut frames = vec![];
+    let mut rest = &data[HEADER..end];
+    // The tag ends at its padding
+    while rest.len() >= HEADER && rest[0] != 0 {
+        let size = match version {
+            4 => unsyncsafe(&rest[4..8]),
+            _ => u32::from_be_bytes(rest[4..8].try_into()?) as usize,
+        };
+        let body = rest
+            .get(HEADER..HEADER + size)
+            .ok_or_else(|| anyhow!("truncated ID3 frame"))?;
+        let frame = Frame {
+            id: rest[..4].try_into()?,
+            flags: [rest[8], rest[9]],
+            body,
+        };
+        // ID3v2.3 frame flags mean something else in ID3v2.4, so only plain frames are kept
+        if version == 4 || frame.flags == [0; 2] {
+            frames.push(frame);
+        }
+        rest = &rest[HEADER + size..];
+    }
+    Ok((frames, audio))
+}
+
+/// The description of a user defined text frame
+fn description(frame: &Frame) -> Option<String> {
+    let (&encoding, text) = frame.body.split_first()?;
+    match encoding {
+        // ISO-8859-1 and UTF-8, which agree on the ASCII descriptions taped uses
+        0 | 3 => {
+            let end = text.iter().position(|&b| b == 0)?;
+            Some(String::from_utf8_lossy(&text[..end]).into_owned())
+        }
+        // UTF-16 with a byte order mark, and big endian UTF-16
+        1 | 2 => {
+            let units: Vec<[u8; 2]> = text
+                .chunks_exact(2)
+                .map(|unit| [unit[0], unit[1]])
+                .take_while(|unit| unit != &[0, 0])
+                .collect();
+            let little_endian = encoding == 1 && units.first() == Some(&[0xff, 0xfe]);
+            let units = units.iter().map(|&unit| match little_endian {
+                true => u16::from_le_bytes(unit),
+                false => u16::from_be_bytes(unit),
+            });
+            let description: String = char::decode_utf16(units)
+                .filter_map(|c| c.ok())
+                .filter(|&c| c != '\u{feff}')
+                .collect();
+            Some(description)
+        }
+        _ => None,
+    }
+}
+
+/// Adds a UTF-8 text frame, with multiple values separated by nulls
+fn text(body: &mut Vec<u8>, id: &[u8; 4], values: &[&str]) {
+    let mut frame = vec![3];
+    frame.extend(values.join("\0").as_bytes());
+    push_frame(body, id, [0; 2], &frame);
+}
+
+fn user_text(body: &mut Vec<u8>, description: &str, value: &str) {
+    let mut frame = vec![3];
+    frame.extend(description.as_bytes());
+    frame.push(0);
+    frame.extend(value.as_bytes());
+    push_frame(body, b"TXXX", [0; 2], &frame);
+}
+
+fn push_frame(body: &mut Vec<u8>, id: &[u8; 4], flags: [u8; 2], frame: &[u8]) {
+    body.extend(id);
+    // Frames too big for a tag make encoding the tag itself fail
+    body.extend(syncsafe(frame.len()).unwrap_or_default());
+    body.extend(flags);
+    body.extend(frame);
+}
+
+/// Encodes a size in 28 bits spread over 4 bytes, so that it never looks like an MPEG sync word
+fn syncsafe(size: usize) -> Result<[u8; 4]> {
+    if size >= 1 << 28 {
+        bail!("ID3 tag of {} bytes is too big", size);
+    }
+    Ok([21, 14, 7, 0].map(|shift| (size >> shift) as u8 & 0x7f))
+}
+
+fn unsyncsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |size, &byte| size << 7 | (byte & 0x7f) as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tags::test::{picture, tags};
+
+    fn frame<'a>(frames: &'a [Frame], id: &[u8; 4]) -> &'a [u8] {
+        frames.iter().find(|frame| &frame.id == id).unwrap().body
+    }
+
+    #[test]
+    fn mp3() {
+        let data = std::fs::read("../../resources/test-audio.mp3").unwrap();
+        let (_, audio) = split(&data).unwrap();
+        let tagged = tag(&data, &tags(), Some(&picture())).unwrap();
+
+        assert!(tagged.starts_with(b"ID3\x04\x00\x00"));
+        let (frames, tagged_audio) = split(&tagged).unwrap();
+        assert_eq!(tagged_audio, audio);
+        assert_eq!(frame(&frames, b"TIT2"), b"\x03Thunderstruck");
+        assert_eq!(frame(&frames, b"TPE1"), b"\x03AC/DC");
+        assert_eq!(frame(&frames, b"TPE2"), b"\x03Kasetophono");
+        assert_eq!(frame(&frames, b"TRCK"), b"\x033/12");
+        assert_eq!(frame(&frames, b"TCON"), b"\x03Rock\x0080s");
+        assert_eq!(frame(&frames, b"TDRC"), b"\x032019-01-20");
+        assert_eq!(
+            frame(&frames, b"WOAS"),
+            b"https://www.youtube.com/watch?v=v2AC41dglnM"
+        );
+        assert!(frame(&frames, b"APIC").starts_with(b"\x03image/png\x00\x03\x00\x89PNG"));
+        // Frames taped doesn't set are kept
+        assert_eq!(frame(&frames, b"TSSE"), b"\x03Lavf57.83.100\x00");
+        let descriptions: Vec<_> = frames
+            .iter()
+            .filter_map(|frame| description(frame))
+            .collect();
+        assert!(descriptions.contains(&"comment".to_string()));
+        assert!(descriptions.contains(&"CURATOR".to_string()));
+
+        // Tagging again replaces the tags rather than adding to them
+        assert_eq!(tag(&tagged, &tags(), Some(&picture())).unwrap(), tagged);
+        let untagged = tag(audio, &tags(), None).unwrap();
+        assert_eq!(split(&untagged).unwrap().1, audio);
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(syncsafe(0x0fff_ffff).unwrap(), [0x7f; 4]);
+        assert_eq!(syncsafe(995).unwrap(), [0, 0, 7, 0x63]);
+        assert_eq!(unsyncsafe(&[0, 0, 7, 0x63]), 995);
+        assert!(syncsafe(1 << 28).is_err());
+
+        let utf16 = Frame {
+            id: *b"TXXX",
+            flags: [0; 2],
+            body: b"\x01\xff\xfeC\x00U\x00R\x00\x00\x00value",
+        };
+        assert_eq!(description(&utf16).as_deref(), Some("CUR"));
+    }
+}
@@ -0,0 +1,364 @@
+use std::ops::Range;
+
+use anyhow::{anyhow, bail};
+
+use super::{Picture, Tags, RELEASE_TYPE};
+use crate::Result;
+
+/// Items taped sets, which replace the ones already in the file. `gnre` is the ID3v1 genre number
+/// some taggers write instead of a genre name.
+const ITEMS: [&[u8; 4]; 9] = [
+    b"\xa9nam", b"\xa9ART", b"\xa9alb", b"aART", b"trkn", b"\xa9gen", b"gnre", b"\xa9day", b"cpil",
+];
+
+/// The namespace of freeform items, which is where MusicBrainz Picard puts its tags too
+const MEAN: &str = "com.apple.iTunes";
+
+/// Names of the freeform items taped sets
+const FREEFORM: [&str; 3] = ["MusicBrainz Album Type", "CURATOR", "SOURCE"];
+
+/// Types of item values
+const IMPLICIT: u32 = 0;
+const UTF8: u32 = 1;
+const JPEG: u32 = 13;
+const PNG: u32 = 14;
+const INTEGER: u32 = 21;
+
+/// An atom of an MP4 file, also known as a box
+struct Atom<'a> {
+    kind: [u8; 4],
+    /// Where the atom starts in its parent
+    offset: usize,
+    /// The length of the size and kind, which is longer for atoms of 4 GiB and more
+    header: usize,
+    raw: &'a [u8],
+}
+
+impl<'a> Atom<'a> {
+    fn body(&self) -> &'a [u8] {
+        &self.raw[self.header..]
+    }
+
+    fn range(&self) -> Range<usize> {
+        self.offset + self.header..self.offset + self.raw.len()
+    }
+}
+
+/// Replaces the iTunes style tags of an M4A file. Items that taped doesn't set are kept, and so is
+/// the cover unless there's a new one. Only the moov atom is rewritten, the audio is left as is.
+pub fn tag(data: &[u8], tags: &Tags, picture: Option<&Picture>) -> Result<Vec<u8>> {
+    let top = atoms(data)?;
+    let moov = top
+        .iter()
+        .find(|atom| &atom.kind == b"moov")
+        .ok_or_else(|| anyhow!("no moov atom"))?;
+
+    let mut body = vec![];
+    let mut udta = None;
+    for atom in atoms(moov.body())? {
+        match &atom.kind {
+            b"udta" => udta = Some(atom.body()),
+            _ => body.extend(atom.raw),
+        }
+    }
+    let udta = user_data(udta.unwrap_or_default(), tags, picture)?;
+    body.extend(boxed(b"udta", &udta));
+
+    // Chunk offsets point into the file, so they move along with the audio after the moov atom
+    let mut tagged_moov = boxed(b"moov", &body);
+    let end = moov.offset + moov.raw.len();
+    let delta = tagged_moov.len() as i64 - moov.raw.len() as i64;
+    shift_offsets(&mut tagged_moov[8..], end, delta)?;
+
+    let mut tagged = Vec::with_capacity(data.len() + tagged_moov.len() - moov.raw.len());
+    tagged.extend(&data[..moov.offset]);
+    tagged.extend(tagged_moov);
+    tagged.extend(&data[end..]);
+    Ok(tagged)
+}
+
+/// Rebuilds the user data atom with a new item list, keeping everything else in it
+fn user_data(udta: &[u8], tags: &Tags, picture: Option<&Picture>) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    let mut meta = None;
+    for atom in atoms(udta)? {
+        match &atom.kind {
+            b"meta" => meta = Some(atom.body()),
+            _ => body.extend(atom.raw),
+        }
+    }
+
+    // The meta atom has a version and flags before its children
+    let mut tagged_meta = vec![0; 4];
+    let mut hdlr = None;
+    let mut ilst = None;
+    for atom in atoms(meta.and_then(|meta| meta.get(4..)).unwrap_or_default())? {
+        match &atom.kind {
+            b"hdlr" => hdlr = Some(atom.raw),
+            b"ilst" => ilst = Some(atom.body()),
+            // Padding left for editing in place, which doesn't apply to a rewritten atom
+            b"free" => {}
+            _ => tagged_meta.extend(atom.raw),
+        }
+    }
+    let hdlr = match hdlr {
+        Some(hdlr) => hdlr.to_vec(),
+        // A metadata handler for iTunes style items, without a name
+        None => boxed(b"hdlr", b"\0\0\0\0\0\0\0\0mdirappl\0\0\0\0\0\0\0\0\0"),
+    };
+    tagged_meta.splice(4..4, hdlr);
+    let items = items(ilst.unwrap_or_default(), tags, picture)?;
+    tagged_meta.extend(boxed(b"ilst", &items));
+
+    body.extend(boxed(b"meta", &tagged_meta));
+    Ok(body)
+}
+
+fn items(ilst: &[u8], tags: &Tags, picture: Option<&Picture>) -> Result<Vec<u8>> {
+    let mut items = vec![];
+    for item in atoms(ilst)? {
+        let replaced = ITEMS.contains(&&item.kind)
+            || (&item.kind == b"covr" && picture.is_some())
+            || (&item.kind == b"----" && FREEFORM.contains(&freeform_name(&item)?.as_str()));
+        if !replaced {
+            items.extend(item.raw);
+        }
+    }
+
+    items.extend(item(b"\xa9nam", UTF8, tags.title.as_bytes()));
+    if let Some(artist) = &tags.artist {
+        items.extend(item(b"\xa9ART", UTF8, artist.as_bytes()));
+    }
+    items.extend(item(b"\xa9alb", UTF8, tags.album.as_bytes()));
+    items.extend(item(b"aART", UTF8, tags.album_artist.as_bytes()));
+    // Padding, the track number, the number of tracks and more padding
+    let mut track = vec![0; 2];
+    track.extend((tags.track as u16).to_be_bytes());
+    track.extend((tags.track_total as u16).to_be_bytes());
+    track.extend([0; 2]);
+    items.extend(item(b"trkn", IMPLICIT, &track));
+    // Players only show a single genre, so they are joined like MusicBrainz Picard does
+    if !tags.genres.is_empty() {
+        items.extend(item(b"\xa9gen", UTF8, tags.genres.join("; ").as_bytes()));
+    }
+    items.extend(item(b"\xa9day", UTF8, tags.date.as_bytes()));
+    items.extend(item(b"cpil", INTEGER, &[1]));
+    items.extend(freeform("MusicBrainz Album Type", RELEASE_TYPE));
+    if let Some(curator) = &tags.curator {
+        items.extend(freeform("CURATOR", curator));
+    }
+    items.extend(freeform("SOURCE", &tags.source));
+    if let Some(picture) = picture {
+        let kind = match picture.mime {
+            "image/png" => PNG,
+            _ => JPEG,
+        };
+        items.extend(item(b"covr", kind, &picture.data));
+    }
+    Ok(items)
+}
+
+/// The name of a freeform item, if it's in the namespace taped uses
+fn freeform_name(item: &Atom) -> Result<String> {
+    let mut mean = None;
+    let mut name = None;
+    for atom in atoms(item.body())? {
+        // Both have a version and flags before the text
+        let text = String::from_utf8_lossy(atom.body().get(4..).unwrap_or_default());
+        match &atom.kind {
+            b"mean" => mean = Some(text),
+            b"name" => name = Some(text),
+            _ => {}
+        }
+    }
+    match (mean, name) {
+        (Some(mean), Some(name)) if mean == MEAN => Ok(name.into_owned()),
+        _ => Ok(String::new()),
+    }
+}
+
+fn item(kind: &[u8; 4], data_type: u32, value: &[u8]) -> Vec<u8> {
+    boxed(kind, &data(data_type, value))
+}
+
+fn freeform(name: &str, value: &str) -> Vec<u8> {
+    let mut body = vec![];
+    for (kind, text) in [(b"mean", MEAN), (b"name", name)] {
+        // A version and flags, then the text
+        let mut atom = vec![0; 4];
+        atom.extend(text.as_bytes());
+        body.extend(boxed(kind, &atom));
+    }
+    body.extend(data(UTF8, value.as_bytes()));
+    boxed(b"----", &body)
+}
+
+/// A value of an item: its type, the default locale and the value itself
+fn data(data_type: u32, value: &[u8]) -> Vec<u8> {
+    let mut body = data_type.to_be_bytes().to_vec();
+    body.extend([0; 4]);
+    body.extend(value);
+    boxed(b"data", &body)
+}
+
+fn boxed(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut atom = Vec::with_capacity(8 + body.len());
+    atom.extend((8 + body.len() as u32).to_be_bytes());
+    atom.extend(kind);
+    atom.extend(body);
+    atom
+}
+
+/// Moves the chunk offsets in the sample tables of `moov` that point at or after `after` by
+/// `delta` bytes
+fn shift_offsets(moov: &mut [u8], after: usize, delta: i64) -> Result<()> {
+    if delta == 0 {
+        return Ok(());
+    }
+    let children: Vec<_> = atoms(moov)?
+        .iter()
+        .map(|atom| (atom.kind, atom.range()))
+        .collect();
+    for (kind, range) in children {
+        let body = &mut moov[range];
+        // The entries come after the version, flags and the number of entries
+        let entries = body.get_mut(8..).unwrap_or_default();
+        match &kind {
+            b"trak" | b"mdia" | b"minf" | b"stbl" => shift_offsets(body, after, delta)?,
+            b"stco" => {
+                for entry in entries.chunks_exact_mut(4) {
+                    let offset = u32::from_be_bytes((&*entry).try_into()?);
+                    if offset as usize >= after {
+                        let shifted = u32::try_from(offset as i64 + delta)?;
+                        entry.copy_from_slice(&shifted.to_be_bytes());
+                    }
+                }
+            }
+            b"co64" => {
+                for entry in entries.chunks_exact_mut(8) {
+                    let offset = u64::from_be_bytes((&*entry).try_into()?);
+                    if offset as usize >= after {
+                        let shifted = u64::try_from(offset as i64 + delta)?;
+                        entry.copy_from_slice(&shifted.to_be_bytes());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Splits data into the atoms it consists of
+fn atoms(data: &[u8]) -> Result<Vec<Atom<'_>>> {
+    let mut atoms = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = data
+            .get(offset..offset + 8)
+            .ok_or_else(|| anyhow!("truncated atom"))?;
+        let kind: [u8; 4] = header[4..].try_into()?;
+        let (header, size) = match u32::from_be_bytes(header[..4].try_into()?) {
+            // The last atom of a file may extend to its end
+            0 => (8, data.len() - offset),
+            1 => {
+                let size = data
+                    .get(offset + 8..offset + 16)
+                    .ok_or_else(|| anyhow!("truncated atom"))?;
+                (16, u64::from_be_bytes(size.try_into()?) as usize)
+            }
+            size => (8, size as usize),
+        };
+        if size < header {
+            bail!("invalid size of {} atom", String::from_utf8_lossy(&kind));
+        }
+        let raw = data
+            .get(offset..offset + size)
+            .ok_or_else(|| anyhow!("truncated {} atom", String::from_utf8_lossy(&kind)))?;
+        atoms.push(Atom {
+            kind,
+            offset,
+            header,
+            raw,
+        });
+        offset += size;
+    }
+    Ok(atoms)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tags::test::{picture, tags};
+
+    fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> &'a [u8] {
+        let atoms = atoms(data).unwrap();
+        atoms.iter().find(|atom| &atom.kind == kind).unwrap().body()
+    }
+
+    /// The values of the items of a file, with freeform items by their name
+    fn values(data: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let meta = child(child(child(data, b"moov"), b"udta"), b"meta");
+        let ilst = child(&meta[4..], b"ilst");
+        atoms(ilst)
+            .unwrap()
+            .iter()
+            .map(|item| {
+                let name = match &item.kind {
+                    b"----" => freeform_name(item).unwrap().into_bytes(),
+                    kind => kind.to_vec(),
+                };
+                (name, child(item.body(), b"data")[8..].to_vec())
+            })
+            .collect()
+    }
+
+    fn value<'a>(values: &'a [(Vec<u8>, Vec<u8>)], name: &[u8]) -> &'a [u8] {
+        let found = values.iter().find(|(item, _)| item == name);
+        &found.unwrap().1
+    }
+
+    #[test]
+    fn m4a() {
+        let data = std::fs::read("../../resources/test-audio.m4a").unwrap();
+        let tagged = tag(&data, &tags(), Some(&picture())).unwrap();
+
+        let values = values(&tagged);
+        assert_eq!(value(&values, b"\xa9nam"), b"Thunderstruck");
+        assert_eq!(value(&values, b"\xa9ART"), b"AC/DC");
+        assert_eq!(value(&values, b"aART"), b"Kasetophono");
+        assert_eq!(value(&values, b"trkn"), [0, 0, 0, 3, 0, 12, 0, 0]);
+        assert_eq!(value(&values, b"\xa9gen"), b"Rock; 80s");
+        assert_eq!(value(&values, b"cpil"), [1]);
+        assert_eq!(value(&values, b"MusicBrainz Album Type"), b"compilation");
+        assert_eq!(value(&values, b"CURATOR"), b"Eleni");
+        assert!(value(&values, b"covr").starts_with(b"\x89PNG"));
+        // Items taped doesn't set are kept, and the ones it does aren't duplicated
+        assert!(value(&values, b"\xa9too").starts_with(b"Lavf"));
+        assert_eq!(values.len(), 14);
+        // The audio before the moov atom is untouched
+        assert_eq!(tagged[..636697], data[..636697]);
+        assert_eq!(tag(&tagged, &tags(), Some(&picture())).unwrap(), tagged);
+    }
+
+    #[test]
+    fn chunk_offsets() {
+        // A file optimised for streaming, with the sample table before the audio
+        let stco = boxed(b"stco", &[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+        let stbl = boxed(b"stbl", &stco);
+        let trak = boxed(b"trak", &boxed(b"mdia", &boxed(b"minf", &stbl)));
+        let mut moov = boxed(b"moov", &trak);
+        let ftyp = boxed(b"ftyp", b"M4A \0\0\0\0");
+        let offset = (ftyp.len() + moov.len() + 8) as u32;
+        let end = moov.len() - 4;
+        moov[end..].copy_from_slice(&offset.to_be_bytes());
+        let data = [ftyp, moov, boxed(b"mdat", b"audio")].concat();
+        assert_eq!(&data[offset as usize..], b"audio");
+
+        let tagged = tag(&data, &tags(), None).unwrap();
+        let minf = child(child(child(&tagged, b"moov"), b"trak"), b"mdia");
+        let stco = child(child(child(minf, b"minf"), b"stbl"), b"stco");
+        let offset = u32::from_be_bytes(stco[8..].try_into().unwrap());
+        assert_eq!(&tagged[offset as usize..], b"audio");
+    }
+}
@@ -0,0 +1,464 @@
+use anyhow::{anyhow, bail};
+use base64::Engine;
+
+use super::{Picture, Tags, RELEASE_TYPE};
+use crate::Result;
+
+/// Comments taped sets, which replace the ones already in the file. Keys are case insensitive.
+const KEYS: [&str; 13] = [
+    "TITLE",
+    "ARTIST",
+    "ALBUM",
+    "ALBUMARTIST",
+    "TRACKNUMBER",
+    "TRACKTOTAL",
+    "TOTALTRACKS",
+    "GENRE",
+    "DATE",
+    "COMPILATION",
+    "RELEASETYPE",
+    "CURATOR",
+    "SOURCE",
+];
+
+/// The comment Ogg files carry their cover in, as a FLAC picture block
+const PICTURE_COMMENT: &str = "METADATA_BLOCK_PICTURE";
+
+/// The vendor of files that didn't have comments yet
+const VENDOR: &[u8] = b"taped";
+
+/// The picture type of front covers
+const FRONT_COVER: u32 = 3;
+
+/// Types of FLAC metadata blocks
+const STREAMINFO: u8 = 0;
+const PADDING: u8 = 1;
+const VORBIS_COMMENT: u8 = 4;
+const PICTURE: u8 = 6;
+
+/// The most bytes a FLAC metadata block can have
+const MAX_BLOCK: usize = (1 << 24) - 1;
+
+/// Header types of Ogg pages
+const CONTINUED: u8 = 0x01;
+
+/// The size of an Ogg page header without its segment table
+const PAGE_HEADER: usize = 27;
+
+/// The lookup table of the CRC of Ogg pages
+const CRC: [u32; 256] = crc_table();
+
+/// Replaces the Vorbis comments of a FLAC file, and its front cover if there's a new one. Blocks
+/// taped doesn't touch are kept, except for padding.
+pub fn tag_flac(data: &[u8], tags: &Tags, picture: Option<&Picture>) -> Result<Vec<u8>> {
+    let mut rest = data
+        .strip_prefix(b"fLaC")
+        .ok_or_else(|| anyhow!("not a FLAC file"))?;
+    let mut blocks = vec![];
+    let mut existing = None;
+    loop {
+        let header = rest
+            .get(..4)
+            .ok_or_else(|| anyhow!("truncated metadata block"))?;
+        let (last, kind) = (header[0] & 0x80 != 0, header[0] & 0x7f);
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let body = rest
+            .get(4..4 + size)
+            .ok_or_else(|| anyhow!("truncated metadata block"))?;
+        match kind {
+            PADDING => {}
+            VORBIS_COMMENT => existing = Some(body),
+            PICTURE if picture.is_some() && body.starts_with(&FRONT_COVER.to_be_bytes()) => {}
+            _ => blocks.push((kind, body)),
+        }
+        rest = &rest[4 + size..];
+        if last {
+            break;
+        }
+    }
+    if blocks.first().map(|&(kind, _)| kind) != Some(STREAMINFO) {
+        bail!("FLAC file without stream info");
+    }
+
+    // Comments go right after the stream info, so that they are found without reading the cover
+    let comments = comments(existing.unwrap_or_default(), tags, None)?;
+    blocks.insert(1, (VORBIS_COMMENT, &comments));
+    let picture = picture.map(picture_block);
+    if let Some(picture) = &picture {
+        blocks.push((PICTURE, picture));
+    }
+
+    let mut tagged = b"fLaC".to_vec();
+    for (index, &(kind, body)) in blocks.iter().enumerate() {
+        if body.len() > MAX_BLOCK {
+            bail!("metadata block of {} bytes is too big", body.len());
+        }
+        let last = match index == blocks.len() - 1 {
+            true => 0x80,
+            false => 0,
+        };
+        tagged.push(kind | last);
+        tagged.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        tagged.extend(body);
+    }
+    tagged.extend(rest);
+    Ok(tagged)
+}
+
+/// Replaces the comment header of an Opus file, with the cover in a comment. The header pages are
+/// written anew and the pages of the audio after them renumbered, the audio itself is left as is.
+pub fn tag_opus(data: &[u8], tags: &Tags, picture: Option<&Picture>) -> Result<Vec<u8>> {
+    let pages = pages(data)?;
+    let head = pages
+        .first()
+        .filter(|page| page.body.starts_with(b"OpusHead"))
+        .ok_or_else(|| anyhow!("not an Opus file"))?;
+
+    // The comment header may span several pages, and the audio starts on the page after it ends
+    let mut packet = vec![];
+    let mut audio = None;
+    for (index, page) in pages.iter().enumerate().skip(1) {
+        packet.extend(page.body);
+        if matches!(page.lacing.last(), Some(&lacing) if lacing < 255) {
+            audio = Some(index + 1);
+            break;
+        }
+    }
+    let audio = audio.ok_or_else(|| anyhow!("truncated comment header"))?;
+    let existing = packet
+        .strip_prefix(b"OpusTags")
+        .ok_or_else(|| anyhow!("missing comment header"))?;
+
+    let mut packet = b"OpusTags".to_vec();
+    packet.extend(comments(existing, tags, picture)?);
+    let (header, count) = paginate(&packet, head.serial, head.sequence.wrapping_add(1));
+    // How far the pages after the comment header move
+    let shift = (count as u32).wrapping_sub(audio as u32 - 1);
+
+    let mut tagged = head.raw.to_vec();
+    tagged.extend(header);
+    for page in &pages[audio..] {
+        if page.serial != head.serial || shift == 0 {
+            tagged.extend(page.raw);
+            continue;
+        }
+        let mut raw = page.raw.to_vec();
+        raw[18..22].copy_from_slice(&page.sequence.wrapping_add(shift).to_le_bytes());
+        seal(&mut raw);
+        tagged.extend(raw);
+    }
+    Ok(tagged)
+}
+
+/// Builds a FLAC picture block for a front cover without a description, in 24 bit colour
+pub fn picture_block(picture: &Picture) -> Vec<u8> {
+    let mut block = vec![];
+    let fields: [&[u8]; 2] = [picture.mime.as_bytes(), b""];
+    block.extend(FRONT_COVER.to_be_bytes());
+    for field in fields {
+        block.extend((field.len() as u32).to_be_bytes());
+        block.extend(field);
+    }
+    let size = picture.data.len() as u32;
+    for value in [picture.width, picture.height, 24, 0, size] {
+        block.extend(value.to_be_bytes());
+    }
+    block.extend(&picture.data);
+    block
+}
+
+/// Replaces the comments taped sets in a Vorbis comment block, keeping the vendor and the others
+fn comments(existing: &[u8], tags: &Tags, picture: Option<&Picture>) -> Result<Vec<u8>> {
+    let (vendor, existing) = match existing.is_empty() {
+        true => (VENDOR, vec![]),
+        false => parse(existing)?,
+    };
+    let mut kept = vec![];
+    for comment in existing {
+        let key = comment.split(|&b| b == b'=').next().unwrap_or_default();
+        let replaced = KEYS.iter().any(|k| k.as_bytes().eq_ignore_ascii_case(key))
+            || (picture.is_some() && PICTURE_COMMENT.as_bytes().eq_ignore_ascii_case(key));
+        if !replaced {
+            kept.push(comment.to_vec());
+        }
+    }
+
+    let mut comments = vec![format!("TITLE={}", tags.title)];
+    if let Some(artist) = &tags.artist {
+        comments.push(format!("ARTIST={}", artist));
+    }
+    comments.push(format!("ALBUM={}", tags.album));
+    comments.push(format!("ALBUMARTIST={}", tags.album_artist));
+    comments.push(format!("TRACKNUMBER={}", tags.track));
+    comments.push(format!("TRACKTOTAL={}", tags.track_total));
+    for genre in &tags.genres {
+        comments.push(format!("GENRE={}", genre));
+    }
+    comments.push(format!("DATE={}", tags.date));
+    comments.push("COMPILATION=1".to_string());
+    comments.push(format!("RELEASETYPE={}", RELEASE_TYPE));
+    if let Some(curator) = &tags.curator {
+        comments.push(format!("CURATOR={}", curator));
+    }
+    comments.push(format!("SOURCE={}", tags.source));
+    if let Some(picture) = picture {
+        let block = base64::engine::general_purpose::STANDARD.encode(picture_block(picture));
+        comments.push(format!("{}={}", PICTURE_COMMENT, block));
+    }
+    kept.extend(comments.into_iter().map(String::into_bytes));
+
+    let mut block = (vendor.len() as u32).to_le_bytes().to_vec();
+    block.extend(vendor);
+    block.extend((kept.len() as u32).to_le_bytes());
+    for comment in kept {
+        block.extend((comment.len() as u32).to_le_bytes());
+        block.extend(comment);
+    }
+    Ok(block)
+}
+
+/// Splits a Vorbis comment block into its vendor and comments
+fn parse(mut block: &[u8]) -> Result<(&[u8], Vec<&[u8]>)> {
+    let vendor = field(&mut block)?;
+    let count = u32::from_le_bytes(take(&mut block, 4)?.try_into()?);
+    let comments = (0..count)
+        .map(|_| field(&mut block))
+        .collect::<Result<_>>()?;
+    Ok((vendor, comments))
+}
+
+/// Reads a field prefixed with its length
+fn field<'a>(block: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let size = u32::from_le_bytes(take(block, 4)?.try_into()?);
+    take(block, size as usize)
+}
+
+fn take<'a>(block: &mut &'a [u8], size: usize) -> Result<&'a [u8]> {
+    if block.len() < size {
+        bail!("truncated comment block");
+    }
+    let (taken, rest) = block.split_at(size);
+    *block = rest;
+    Ok(taken)
+}
+
+/// A page of an Ogg file
+struct Page<'a> {
+    serial: u32,
+    sequence: u32,
+    /// The sizes of the segments of the packets on the page
+    lacing: &'a [u8],
+    body: &'a [u8],
+    raw: &'a [u8],
+}
+
+fn pages(mut data: &[u8]) -> Result<Vec<Page<'_>>> {
+    let mut pages = vec![];
+    while !data.is_empty() {
+        if !data.starts_with(b"OggS") || data.len() < PAGE_HEADER {
+            bail!("invalid Ogg page");
+        }
+        let segments = data[PAGE_HEADER - 1] as usize;
+        let lacing = data
+            .get(PAGE_HEADER..PAGE_HEADER + segments)
+            .ok_or_else(|| anyhow!("truncated Ogg page"))?;
+        let size = PAGE_HEADER + segments + lacing.iter().map(|&l| l as usize).sum::<usize>();
+        let raw = data
+            .get(..size)
+            .ok_or_else(|| anyhow!("truncated Ogg page"))?;
+        pages.push(Page {
+            serial: u32::from_le_bytes(raw[14..18].try_into()?),
+            sequence: u32::from_le_bytes(raw[18..22].try_into()?),
+            lacing,
+            body: &raw[PAGE_HEADER + segments..],
+            raw,
+        });
+        data = &data[size..];
+    }
+    Ok(pages)
+}
+
+/// Splits a header packet over as many pages as it needs, returning them and how many there are
+fn paginate(packet: &[u8], serial: u32, sequence: u32) -> (Vec<u8>, usize) {
+    // Segments are 255 bytes, except for a shorter one that ends the packet
+    let mut lacing = vec![255; packet.len() / 255];
+    lacing.push((packet.len() % 255) as u8);
+
+    let mut pages = vec![];
+    let mut offset = 0;
+    let chunks = lacing.chunks(255).count();
+    for (index, lacing) in lacing.chunks(255).enumerate() {
+        let size: usize = lacing.iter().map(|&l| l as usize).sum();
+        let header_type = match index {
+            0 => 0,
+            _ => CONTINUED,
+        };
+        // Header pages have no position, and pages on which no packet ends have none at all
+        let granule: i64 = match index == chunks - 1 {
+            true => 0,
+            false => -1,
+        };
+        let mut page = b"OggS\0".to_vec();
+        page.push(header_type);
+        page.extend(granule.to_le_bytes());
+        page.extend(serial.to_le_bytes());
+        page.extend(sequence.wrapping_add(index as u32).to_le_bytes());
+        page.extend([0; 4]);
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        page.extend(&packet[offset..offset + size]);
+        seal(&mut page);
+        pages.extend(page);
+        offset += size;
+    }
+    (pages, chunks)
+}
+
+/// Sets the checksum of a page, which covers the whole page with the checksum itself zeroed
+fn seal(page: &mut [u8]) {
+    page[22..26].fill(0);
+    let crc = crc(page);
+    page[22..26].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| {
+        (crc << 8) ^ CRC[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = (index as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 0x8000_0000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x04c1_1db7,
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tags::test::{picture, tags};
+
+    fn comment_block(comments: &[&str]) -> Vec<u8> {
+        let mut block = 6u32.to_le_bytes().to_vec();
+        block.extend(b"ffmpeg");
+        block.extend((comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend((comment.len() as u32).to_le_bytes());
+            block.extend(comment.as_bytes());
+        }
+        block
+    }
+
+    fn strings(block: &[u8]) -> Vec<String> {
+        let (vendor, comments) = parse(block).unwrap();
+        assert_eq!(vendor, b"ffmpeg");
+        comments
+            .iter()
+            .map(|comment| String::from_utf8_lossy(comment).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn flac() {
+        let mut data = b"fLaC".to_vec();
+        data.extend([STREAMINFO, 0, 0, 34]);
+        data.extend([0; 34]);
+        let comments = comment_block(&["title=Old", "ENCODER=Lavf58.76.100"]);
+        data.extend([VORBIS_COMMENT, 0, 0, comments.len() as u8]);
+        data.extend(comments);
+        data.extend([0x80 | PADDING, 0, 0, 8]);
+        data.extend([0; 8]);
+        data.extend(b"frames");
+
+        let tagged = tag_flac(&data, &tags(), Some(&picture())).unwrap();
+        assert!(tagged.starts_with(b"fLaC\0\0\0\x22"));
+        assert!(tagged.ends_with(b"frames"));
+        let comments = &tagged[42..];
+        assert_eq!(comments[0], VORBIS_COMMENT);
+        let size = u32::from_be_bytes([0, comments[1], comments[2], comments[3]]) as usize;
+        let comments = strings(&comments[4..4 + size]);
+        assert_eq!(comments[0], "ENCODER=Lavf58.76.100");
+        assert_eq!(comments[1], "TITLE=Thunderstruck");
+        assert!(comments.contains(&"GENRE=80s".to_string()));
+        assert!(comments.contains(&"RELEASETYPE=compilation".to_string()));
+        assert!(!comments.contains(&"title=Old".to_string()));
+        // The cover is the last block
+        let picture = picture_block(&picture());
+        let end = tagged.len() - b"frames".len();
+        assert_eq!(tagged[end - picture.len() - 4], 0x80 | PICTURE);
+        assert_eq!(&tagged[end - picture.len()..end], picture);
+
+        assert_eq!(tag_flac(&tagged, &tags(), None).unwrap(), tagged);
+        assert!(tag_flac(b"ID3\x04", &tags(), None).is_err());
+    }
+
+    #[test]
+    fn opus() {
+        let head = paginate(b"OpusHead\x01\x02\x38\x01\x80\xbb\0\0\0\0\0", 7, 0).0;
+        let (comments, _) = paginate(
+            &[&b"OpusTags"[..], &comment_block(&["ARTIST=Old"])].concat(),
+            7,
+            1,
+        );
+        let mut audio = b"OggS\0\x04".to_vec();
+        audio.extend(960i64.to_le_bytes());
+        audio.extend(7u32.to_le_bytes());
+        audio.extend(2u32.to_le_bytes());
+        audio.extend([0; 4]);
+        audio.extend([1, 5]);
+        audio.extend(b"audio");
+        seal(&mut audio);
+        let data = [head, comments, audio].concat();
+
+        // A cover big enough for the comment header to span two pages
+        let mut picture = picture();
+        picture.data.resize(70_000, 0);
+        let tagged = tag_opus(&data, &tags(), Some(&picture)).unwrap();
+        let tagged_pages = pages(&tagged).unwrap();
+        assert_eq!(tagged_pages.len(), 4);
+        for (sequence, page) in tagged_pages.iter().enumerate() {
+            assert_eq!(page.sequence, sequence as u32);
+            let mut raw = page.raw.to_vec();
+            seal(&mut raw);
+            assert_eq!(raw, page.raw);
+        }
+        assert_eq!(tagged_pages[2].raw[5], CONTINUED);
+        assert_eq!(tagged_pages[3].body, b"audio");
+        assert_eq!(&tagged_pages[3].raw[6..14], &960i64.to_le_bytes());
+
+        let packet = [tagged_pages[1].body, tagged_pages[2].body].concat();
+        let comments = strings(&packet[8..]);
+        assert_eq!(comments[0], "TITLE=Thunderstruck");
+        assert_eq!(comments[1], "ARTIST=AC/DC");
+        assert!(comments
+            .last()
+            .unwrap()
+            .starts_with("METADATA_BLOCK_PICTURE="));
+
+        // Without a new cover the old one is kept
+        let retagged = tag_opus(&tagged, &tags(), None).unwrap();
+        assert_eq!(pages(&retagged).unwrap().len(), 4);
+        let tagged = tag_opus(&data, &tags(), None).unwrap();
+        assert_eq!(pages(&tagged).unwrap()[2].sequence, 2);
+        assert_eq!(tag_opus(&tagged, &tags(), None).unwrap(), tagged);
+    }
+
+    #[test]
+    fn checksums() {
+        // The check value of the CRC-32 variant without reflection or a final XOR
+        assert_eq!(crc(b"123456789"), 0x89a1_897f);
+    }
+}
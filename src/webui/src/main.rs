@@ -1,6 +1,6 @@
-use std::collections::HashMap;
 use std::fmt::Write;
 
+use serde::Deserialize;
 use uuid::Uuid;
 use wasm_bindgen_futures::spawn_local;
 use yew::prelude::*;
@@ -9,13 +9,14 @@ use kasetophono::Cassette;
 
 enum Msg {
     Play(Uuid),
-    Cassettes(HashMap<Uuid, Cassette>),
+    Download(Uuid),
+    Cassettes(Vec<Cassette>),
     Stop,
 }
 
 struct Model {
     link: ComponentLink<Self>,
-    cassettes: Vec<(Uuid, Cassette)>,
+    cassettes: Vec<Cassette>,
 }
 
 impl Component for Model {
@@ -37,15 +38,17 @@ impl Component for Model {
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
             Msg::Cassettes(cassettes) => {
-                self.cassettes = cassettes.into_iter().collect();
-                self.cassettes
-                    .sort_by(|a, b| b.1.created_at.cmp(&a.1.created_at));
+                self.cassettes = cassettes;
                 true
             }
             Msg::Play(uuid) => {
                 spawn_local(play_cassette(uuid));
                 false
             }
+            Msg::Download(uuid) => {
+                spawn_local(download_cassette(uuid));
+                false
+            }
             Msg::Stop => {
                 spawn_local(stop());
                 false
@@ -60,21 +63,33 @@ impl Component for Model {
     fn view(&self) -> Html {
         html! {
             <div>
-                <img class="title" src="https://3.bp.blogspot.com/-xTPGTrjKbcc/WbGOKSAWMQI/AAAAAAAAPQM/UY9fma6zC9kpAWKK8Vd1xbJhKVxiDHh2wCK4BGAYYCw/s600/kasetophono.png"/>
+                <img class="title" src="/api/v1/logo?size=512"/>
                 <button class="stop" onclick=self.link.callback(|_| Msg::Stop)>{ "Stop" }</button>
                 <table>
                     <thead>
                         <tr>
+                            <th></th>
+                            <th></th>
                             <th></th>
                             <th>{"Title"}</th>
                             <th>{"Created At"}</th>
                         </tr>
                     </thead>
                     <tbody>
-                        {for self.cassettes.iter().map(|&(uuid, ref cassette)| {
+                        {for self.cassettes.iter().map(|cassette| {
+                            let uuid = cassette.uuid;
+                            let cover = match cassette.image_url {
+                                Some(_) => {
+                                    let src = format!("/api/v1/cassettes/{}/cover?size=64", uuid);
+                                    html! { <img class="cover" loading="lazy" src=src/> }
+                                }
+                                None => html! {},
+                            };
                             html! {
                                 <tr key={uuid.to_string()}>
                                     <td><button class="play" onclick=self.link.clone().callback(move |_| Msg::Play(uuid))>{"Play"}</button></td>
+                                    <td><button class="download" onclick=self.link.clone().callback(move |_| Msg::Download(uuid))>{"Download"}</button></td>
+                                    <td>{cover}</td>
                                     <td>{&cassette.name}</td>
                                     <td>{&cassette.created_at[0..10]}</td>
                                 </tr>
@@ -87,51 +102,80 @@ impl Component for Model {
     }
 }
 
-async fn stop() {
+/// Sends a request to the taped API, logging any error to the console
+async fn api(
+    method: reqwest::Method,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> Option<String> {
     let window = web_sys::window().unwrap();
-    let mut path = window.location().origin().unwrap();
-    path.push_str("/api/stop");
-
-    reqwest::Client::new()
-        .get(path)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
+    let mut url = window.location().origin().unwrap();
+    write!(url, "/api/v1{}", path).expect("infallible");
+
+    let mut request = reqwest::Client::new().request(method, url);
+    if let Some(body) = body {
+        request = request
+            .header("content-type", "application/json")
+            .body(body.to_string());
+    }
+    let result = async {
+        let response = request.send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        Ok::<_, reqwest::Error>((status, text))
+    }
+    .await;
+
+    match result {
+        Ok((status, text)) if status.is_success() => Some(text),
+        Ok((status, text)) => {
+            web_sys::console::error_1(&format!("{} {}: {}", status, path, text).into());
+            None
+        }
+        Err(err) => {
+            web_sys::console::error_1(&format!("{}: {}", path, err).into());
+            None
+        }
+    }
+}
+
+async fn stop() {
+    api(reqwest::Method::POST, "/player/stop", None).await;
 }
 
 async fn play_cassette(uuid: Uuid) {
-    let window = web_sys::window().unwrap();
-    let mut path = window.location().origin().unwrap();
-    write!(path, "/api/play/{}", uuid).expect("infallible");
-
-    reqwest::Client::new()
-        .get(path)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
+    let body = serde_json::json!({ "cassette": uuid });
+    api(reqwest::Method::POST, "/player/play", Some(body)).await;
 }
 
-async fn fetch_cassettes() -> HashMap<Uuid, Cassette> {
-    let window = web_sys::window().unwrap();
-    let mut path = window.location().origin().unwrap();
-    path.push_str("/api/cassettes");
-
-    let res = reqwest::Client::new()
-        .get(path)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap();
-
-    serde_json::from_str(&res).unwrap()
+/// Queues a download of the cassette into the library of taped
+async fn download_cassette(uuid: Uuid) {
+    let body = serde_json::json!({ "cassette": uuid });
+    api(reqwest::Method::POST, "/downloads", Some(body)).await;
+}
+
+/// A page of the cassette listing
+#[derive(Deserialize)]
+struct Page {
+    cassettes: Vec<Cassette>,
+    next_cursor: Option<usize>,
+}
+
+/// Fetches the whole catalog, newest cassettes first
+async fn fetch_cassettes() -> Vec<Cassette> {
+    let mut cassettes = vec![];
+    let mut path = String::from("/cassettes?sort=newest&limit=500");
+    while let Some(res) = api(reqwest::Method::GET, &path, None).await {
+        let page: Page = serde_json::from_str(&res).unwrap();
+        cassettes.extend(page.cassettes);
+        match page.next_cursor {
+            Some(cursor) => {
+                path = format!("/cassettes?sort=newest&limit=500&cursor={}", cursor);
+            }
+            None => break,
+        }
+    }
+    cassettes
 }
 
 fn main() {